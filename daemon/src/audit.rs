@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::time::SystemTime;
+use log::{debug, error};
+use tinyjson::JsonValue;
+
+///Append-only trail of the handled requests, the file being moved to `<path>.1` once its max size is reached
+pub struct AuditLog {
+    pub path: String,
+    pub max_size: u64,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct AuditEntry {
+    //rfc3339 date
+    pub date: String,
+    pub sender: String,
+    pub user: Option<String>,
+    pub command: String,
+    //codes are never recorded
+    pub arguments: Vec<String>,
+    pub outcome: String,
+    pub tunnels: Vec<u32>,
+}
+
+impl AuditEntry {
+    pub fn new(sender: &str, command: &str) -> Self {
+        AuditEntry {
+            date: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            sender: sender.to_string(),
+            user: None,
+            command: command.to_lowercase(),
+            arguments: vec!(),
+            outcome: String::new(),
+            tunnels: vec!(),
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut fields = HashMap::new();
+        fields.insert("date".to_string(), JsonValue::String(self.date.clone()));
+        fields.insert("sender".to_string(), JsonValue::String(self.sender.clone()));
+        fields.insert("user".to_string(), self.user.clone().map(JsonValue::String).unwrap_or(JsonValue::Null));
+        fields.insert("command".to_string(), JsonValue::String(self.command.clone()));
+        fields.insert("arguments".to_string(), JsonValue::Array(self.arguments.iter().cloned().map(JsonValue::String).collect()));
+        fields.insert("outcome".to_string(), JsonValue::String(self.outcome.clone()));
+        fields.insert("tunnels".to_string(), JsonValue::Array(self.tunnels.iter().map(|t| JsonValue::Number(*t as f64)).collect()));
+        JsonValue::Object(fields)
+    }
+
+    fn from_json(json: &JsonValue) -> Option<Self> {
+        let fields: &HashMap<String, JsonValue> = json.get()?;
+        let string_field = |name: &str| fields.get(name).and_then(|value| value.get::<String>()).cloned();
+        let array_field = |name: &str| fields.get(name).and_then(|value| value.get::<Vec<JsonValue>>()).cloned().unwrap_or_default();
+        Some(AuditEntry {
+            date: string_field("date")?,
+            sender: string_field("sender")?,
+            user: string_field("user"),
+            command: string_field("command")?,
+            arguments: array_field("arguments").iter().filter_map(|a| a.get::<String>().cloned()).collect(),
+            outcome: string_field("outcome")?,
+            tunnels: array_field("tunnels").iter().filter_map(|t| t.get::<f64>().map(|t| *t as u32)).collect(),
+        })
+    }
+}
+
+impl Display for AuditEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} ({}) {}", self.date, self.user.as_deref().unwrap_or("unknown"), self.sender, self.command)?;
+        for argument in &self.arguments {
+            write!(f, " {}", argument)?;
+        }
+        write!(f, ": {}", self.outcome)?;
+        if !self.tunnels.is_empty() {
+            let tunnels: Vec<String> = self.tunnels.iter().map(|t| t.to_string()).collect();
+            write!(f, " - tunnel {}", tunnels.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+impl AuditLog {
+    pub fn new(path: &str, max_size: u64) -> Self {
+        AuditLog { path: path.to_string(), max_size }
+    }
+
+    pub fn append(&self, entry: &AuditEntry) {
+        let line = match entry.to_json().stringify() {
+            Ok(line) => line,
+            Err(e) => {
+                error!("append: cannot serialize audit entry - error: {:?}",e);
+                return;
+            }
+        };
+        let size = std::fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 >= self.max_size {
+            debug!("append: rotating audit file {}",self.path);
+            if let Err(e) = std::fs::rename(&self.path, self.rotated_path()) {
+                error!("append: cannot rotate audit file {} - error: {:?}",self.path,e);
+            }
+        }
+        let result = OpenOptions::new().create(true).append(true).open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", line));
+        if let Err(e) = result {
+            error!("append: cannot write audit file {} - error: {:?}",self.path,e);
+        }
+    }
+
+    ///Returns the latest entries, oldest first
+    pub fn latest(&self, count: usize) -> Vec<AuditEntry> {
+        let mut entries: Vec<AuditEntry> = [self.rotated_path(), self.path.clone()].iter()
+            .flat_map(|path| {
+                let mut content = String::new();
+                if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut content)) {
+                    debug!("latest: cannot read audit file {} - error: {:?}",path,e);
+                }
+                content.lines()
+                    .filter_map(|line| line.parse::<JsonValue>().ok())
+                    .filter_map(|json| AuditEntry::from_json(&json))
+                    .collect::<Vec<AuditEntry>>()
+            })
+            .collect();
+        let skipped = entries.len().saturating_sub(count);
+        entries.drain(..skipped);
+        entries
+    }
+
+    fn rotated_path(&self) -> String {
+        format!("{}.1", self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn audit_log(name: &str, max_size: u64) -> AuditLog {
+        let path = std::env::temp_dir().join(format!("telco-vecchio-audit-{}-{}", name, std::process::id()));
+        let audit_log = AuditLog::new(path.to_str().unwrap(), max_size);
+        let _ = std::fs::remove_file(&audit_log.path);
+        let _ = std::fs::remove_file(audit_log.rotated_path());
+        audit_log
+    }
+
+    fn entry(outcome: &str) -> AuditEntry {
+        let mut entry = AuditEntry::new("+33612345678", "Close");
+        entry.user = Some("alice".to_string());
+        entry.arguments = vec!("3".to_string());
+        entry.outcome = outcome.to_string();
+        entry.tunnels = vec!(3);
+        entry
+    }
+
+    fn outcomes(entries: &[AuditEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.outcome.as_str()).collect()
+    }
+
+    #[test]
+    fn latest_entries_are_returned_oldest_first() {
+        let audit_log = audit_log("latest", 100000);
+        assert!(audit_log.latest(5).is_empty());
+        for outcome in ["1", "2", "3"] {
+            audit_log.append(&entry(outcome));
+        }
+        let entries = audit_log.latest(2);
+        assert_eq!(outcomes(&entries), vec!("2", "3"));
+        //all fields are read back
+        let mut expected = entry("2");
+        expected.date = entries[0].date.clone();
+        assert_eq!(entries[0], expected);
+        assert_eq!(outcomes(&audit_log.latest(10)), vec!("1", "2", "3"));
+        assert!(entries[1].to_string().ends_with("alice (+33612345678) close 3: 3 - tunnel 3"), "{}", entries[1]);
+    }
+
+    #[test]
+    fn audit_trail_is_rotated() {
+        let audit_log = audit_log("rotation", 1000);
+        for i in 0..20 {
+            audit_log.append(&entry(&i.to_string()));
+        }
+        assert!(std::fs::metadata(&audit_log.path).unwrap().len() < 1000);
+        assert!(std::fs::metadata(audit_log.rotated_path()).is_ok());
+        assert_eq!(outcomes(&audit_log.latest(3)), vec!("17", "18", "19"));
+    }
+
+    #[test]
+    fn corrupt_lines_are_skipped() {
+        let audit_log = audit_log("corrupt", 100000);
+        audit_log.append(&entry("1"));
+        //line whose writing was interrupted, then a line missing fields
+        let mut file = OpenOptions::new().append(true).open(&audit_log.path).unwrap();
+        writeln!(file, r#"{{"date":"2024-01-01T00:00:00Z","sender":"+336"#).unwrap();
+        writeln!(file, r#"{{"date":"2024-01-01T00:00:00Z","sender":"+33612345678"}}"#).unwrap();
+        audit_log.append(&entry("2"));
+        assert_eq!(outcomes(&audit_log.latest(10)), vec!("1", "2"));
+    }
+}
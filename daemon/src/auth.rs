@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use hmac::{Hmac, Mac};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use crate::{common, file_utils};
+use crate::common::Error::Authentication;
+use crate::user::User;
+
+pub const DEFAULT_CODE_REQUIRED_COMMANDS: [&str; 3] = ["open", "reboot", "shutdown"];
+const TOTP_TIME_STEP_SEC: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+//number of time steps accepted before and after the current one, covering clock drift and sms delivery delay
+const TOTP_ACCEPTED_STEP_DRIFT: u64 = 1;
+
+///Last accepted totp step per user, persisted so that a totp code cannot be replayed after a restart
+pub struct TotpSteps {
+    pub path: String,
+    steps: HashMap<String, u64>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct TotpStepsContent {
+    #[serde(default)]
+    step: HashMap<String, u64>,
+}
+
+impl TotpSteps {
+    ///Reads the steps of the file, none being known if the file does not exist
+    pub fn load(path: &str) -> Self {
+        let steps = file_utils::load_toml::<TotpStepsContent>(path).step;
+        info!("load: last totp steps of {} users",steps.len());
+        TotpSteps { path: path.to_string(), steps }
+    }
+
+    pub fn last_accepted(&self, user: &str) -> Option<u64> {
+        self.steps.get(user).copied()
+    }
+
+    pub fn accept(&mut self, user: &str, step: u64) {
+        self.steps.insert(user.to_string(), step);
+        let content = TotpStepsContent { step: self.steps.clone() };
+        if let Err(e) = file_utils::save_toml(&self.path, &content) {
+            error!("accept: cannot write totp step file {} - error: {:?}",self.path,e);
+        }
+    }
+}
+
+///Returns true if the user has to add a code to the command, that is when a pin or a totp secret is configured
+/// and the command is part of the user's code required commands
+pub fn is_code_required(user: &User, command: &str) -> bool {
+    if user.pin.is_none() && user.totp_secret.is_none() {
+        return false;
+    }
+    match &user.code_required_commands {
+        Some(commands) => commands.iter().any(|c| c.eq_ignore_ascii_case(command)),
+        None => DEFAULT_CODE_REQUIRED_COMMANDS.iter().any(|c| c.eq_ignore_ascii_case(command)),
+    }
+}
+
+///Returns true if the word looks like a code the user may send, that is digits only with the pin length
+/// or the totp code length, so that the last argument of a command sent without code is not taken for a code
+pub fn is_code_shaped(user: &User, word: &str) -> bool {
+    if word.is_empty() || !word.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+    user.pin.as_ref().map(|pin| pin.len() == word.len()).unwrap_or(false)
+        || (user.totp_secret.is_some() && word.len() == TOTP_DIGITS as usize)
+}
+
+///Checks the code against the user's static pin, then against the user's totp secret at the given unix time,
+/// totp steps up to the last accepted one being refused so that a code cannot be replayed.
+/// Returns the accepted totp step, if any
+pub fn check_code(user: &User, code: Option<&str>, unix_time: u64, last_accepted_step: Option<u64>) -> common::Result<Option<u64>> {
+    let code = code.ok_or_else(|| {
+        error!("check_code - no code provided by {}",user.name);
+        Authentication("a code is required for this command".to_string())
+    })?;
+    if let Some(pin) = &user.pin {
+        if constant_time_eq(pin.as_bytes(), code.as_bytes()) {
+            debug!("check_code - pin accepted for {}",user.name);
+            return Ok(None);
+        }
+    }
+    if let Some(secret) = &user.totp_secret {
+        let key = decode_base32(secret).ok_or_else(|| {
+            error!("check_code - invalid totp secret configured for {}",user.name);
+            Authentication("the code cannot be checked".to_string())
+        })?;
+        let step = unix_time / TOTP_TIME_STEP_SEC;
+        let accepted_step = (step.saturating_sub(TOTP_ACCEPTED_STEP_DRIFT)..=step + TOTP_ACCEPTED_STEP_DRIFT)
+            .find(|step| {
+                let expected = format!("{:0width$}", hotp(&key, *step, TOTP_DIGITS), width = TOTP_DIGITS as usize);
+                constant_time_eq(expected.as_bytes(), code.as_bytes())
+            });
+        if let Some(accepted_step) = accepted_step {
+            if last_accepted_step.map(|last| accepted_step <= last).unwrap_or(false) {
+                error!("check_code - totp code already used by {}",user.name);
+                return Err(Authentication("the code was already used".to_string()));
+            }
+            debug!("check_code - totp code accepted for {}",user.name);
+            return Ok(Some(accepted_step));
+        }
+    }
+    error!("check_code - invalid code provided by {}",user.name);
+    Err(Authentication("the code is invalid".to_string()))
+}
+
+///HOTP value (RFC 4226) of the counter, truncated to the number of digits
+pub fn hotp(key: &[u8], counter: u64, digits: u32) -> u32 {
+    //hmac accepts keys of any length
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac key of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    //dynamic truncation
+    let offset = (hash[19] & 0x0f) as usize;
+    let value = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    value % 10u32.pow(digits)
+}
+
+///Decodes RFC 4648 base32, as used by authenticator applications, ignoring padding, spaces and case
+pub fn decode_base32(input: &str) -> Option<Vec<u8>> {
+    let mut output = vec!();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in input.chars().filter(|c| *c != '=' && !c.is_whitespace()) {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(output)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(pin: Option<&str>, totp_secret: Option<&str>) -> User {
+        User {
+            name: "alice".to_string(),
+            phone_number: "+33123456789".to_string(),
+            email: "alice@mail.com".to_string(),
+            call: None,
+            admin: None,
+            pin: pin.map(|s| s.to_string()),
+            totp_secret: totp_secret.map(|s| s.to_string()),
+            code_required_commands: None,
+            role: None,
+            allowed_commands: None,
+            applications: None,
+            tunnel_max_duration_sec: None,
+        }
+    }
+
+    #[test]
+    fn hotp_rfc4226_test_vectors() {
+        let expected = [755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489];
+        for (counter, value) in expected.iter().enumerate() {
+            assert_eq!(hotp(b"12345678901234567890", counter as u64, 6), *value);
+        }
+    }
+
+    #[test]
+    fn totp_rfc6238_test_vectors() {
+        //"12345678901234567890" in base32
+        let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+        let key = decode_base32(secret).unwrap();
+        assert_eq!(key, b"12345678901234567890");
+        assert_eq!(hotp(&key, 59 / 30, 8), 94287082);
+        assert_eq!(hotp(&key, 1111111109 / 30, 8), 7081804);
+        assert_eq!(hotp(&key, 1234567890 / 30, 8), 89005924);
+        assert_eq!(hotp(&key, 20000000000 / 30, 8), 65353130);
+
+        let user = user(None, Some(secret));
+        assert_eq!(check_code(&user, Some("081804"), 1111111109, None).unwrap(), Some(1111111109 / 30));
+        //previous and next time steps are accepted
+        assert!(check_code(&user, Some("081804"), 1111111109 + 30, None).is_ok());
+        assert!(check_code(&user, Some("081804"), 1111111109 - 30, None).is_ok());
+        assert!(check_code(&user, Some("081804"), 1111111109 + 90, None).is_err());
+    }
+
+    #[test]
+    fn totp_code_cannot_be_replayed() {
+        let user = user(None, Some("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"));
+        let step = check_code(&user, Some("081804"), 1111111109, None).unwrap();
+        assert!(check_code(&user, Some("081804"), 1111111109, step).is_err());
+        //a code of an earlier step is refused as well
+        let next_step_code = format!("{:06}", hotp(b"12345678901234567890", 1111111109 / 30 + 1, 6));
+        let step = check_code(&user, Some(&next_step_code), 1111111109, step).unwrap();
+        assert!(check_code(&user, Some("081804"), 1111111109, step).is_err());
+    }
+
+    #[test]
+    fn accepted_totp_steps_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("telco-vecchio-totp-steps-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut totp_steps = TotpSteps::load(path.to_str().unwrap());
+        assert_eq!(totp_steps.last_accepted("alice"), None);
+        totp_steps.accept("alice", 37037036);
+        totp_steps.accept("alice", 37037037);
+        totp_steps.accept("bob", 1);
+        let totp_steps = TotpSteps::load(path.to_str().unwrap());
+        assert_eq!(totp_steps.last_accepted("alice"), Some(37037037));
+        assert_eq!(totp_steps.last_accepted("bob"), Some(1));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn code_shape() {
+        let user = user(Some("1234"), Some("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"));
+        assert!(is_code_shaped(&user, "1234"));
+        assert!(is_code_shaped(&user, "123456"));
+        assert!(!is_code_shaped(&user, "nas"));
+        assert!(!is_code_shaped(&user, "12345"));
+        assert!(!is_code_shaped(&user, "12a4"));
+        let pin_user = User { totp_secret: None, ..user };
+        assert!(!is_code_shaped(&pin_user, "123456"));
+    }
+
+    #[test]
+    fn code_required_commands() {
+        assert!(!is_code_required(&user(None, None), "open"));
+        let mut user = user(Some("1234"), None);
+        assert!(is_code_required(&user, "open"));
+        assert!(is_code_required(&user, "Reboot"));
+        assert!(!is_code_required(&user, "status"));
+        assert_eq!(check_code(&user, Some("1234"), 0, None).unwrap(), None);
+        assert!(check_code(&user, Some("4321"), 0, None).is_err());
+        assert!(check_code(&user, None, 0, None).is_err());
+        user.code_required_commands = Some(vec!("status".to_string()));
+        assert!(is_code_required(&user, "status"));
+        assert!(!is_code_required(&user, "open"));
+    }
+}
@@ -1,48 +1,107 @@
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::io;
 use std::time::{Duration, SystemTime};
 use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
 use surge_ping::SurgeError;
 use tokio::process::Child;
+use tokio::sync::mpsc::UnboundedReceiver;
 use crate::application::Application;
-use crate::common::Error::{IoError, PingError};
-use crate::email_utils::EmailConfig;
+use crate::audit::AuditLog;
+use crate::auth::TotpSteps;
+use crate::common::Error::{AtCommand, Io, Ping};
+use crate::confirmation::{ConfirmationConfig, PendingActions};
+use crate::email_utils;
+use crate::email_utils::{EmailConfig, OutgoingEmail};
+use crate::guest::{Guest, GuestRegister};
 use crate::init::InitConfig;
+use crate::modem::{AtError, Modem, UnsolicitedResult};
+use crate::outbox::Outbox;
+use crate::pdu::{DeliveryStatus, SmsStatusReport};
+use crate::rate_limit::{RateLimitConfig, RateLimiter};
 use crate::sms_utils;
-use crate::sms_utils::{OutgoingSms, SmsConfig};
+use crate::sms_utils::{ConcatenatedSmsBuffer, OutgoingSms, ReceivedSmsCache, SmsConfig};
 use crate::ssh_utils::SshConfig;
 use crate::status::Status;
+use crate::unknown_sender::{DigestChannel, UnknownSenderConfig, UnknownSenderDigest};
 use crate::user::User;
+use crate::ussd_utils;
+use crate::ussd_utils::UssdConfig;
+
+const DEFAULT_SMS_DELIVERY_MAX_RETRY: u32 = 1;
+const PENDING_DELIVERY_TIMEOUT_SEC: u64 = 3600;
+const DEFAULT_SMS_OUTBOX_MAX_RETRY: u32 = 10;
 
 #[derive(Debug)]
 pub enum Error {
-    IoError(io::Error),
-    SmsInitError,
-    SmsReadingError,
-    SmsSendingError,
-    SystemCommandExecutionError,
-    SshTunnelUrlParsingError,
+    Io(io::Error),
+    SmsInit,
+    SmsReading,
+    SmsSending,
+    PduParsing(String),
+    SystemCommandExecution,
+    SshTunnelUrlParsing,
     SshTunnelUrlSetupTimeout,
-    SshTunnelServiceError(String),
-    ConfigurationParsingError(toml::de::Error),
-    QmiResponseParsingError(String),
+    SshTunnelService(String),
+    ConfigurationParsing(toml::de::Error),
+    QmiResponseParsing(String),
     SenderNotAllowed(String),
-    InvalidRequestError(String),
-    DomainNameResolutionError,
-    PingError(SurgeError),
+    InvalidRequest(String),
+    DomainNameResolution,
+    Ping(SurgeError),
     InvalidStatus(String),
+    AtCommand(AtError),
+    Ussd(String),
+    Authentication(String),
+    CommandNotAllowed(String),
+    //message to send back to the sender, only set on the first rejected request
+    RateLimitExceeded(Option<String>),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::SmsInit => write!(f, "SMS initialisation failed"),
+            Error::SmsReading => write!(f, "SMS reading failed"),
+            Error::SmsSending => write!(f, "SMS sending failed"),
+            Error::PduParsing(s) => write!(f, "invalid PDU: {}", s),
+            Error::SystemCommandExecution => write!(f, "system command failed"),
+            Error::SshTunnelUrlParsing => write!(f, "cannot read SSH tunnel url"),
+            Error::SshTunnelUrlSetupTimeout => write!(f, "SSH tunnel setup timed out"),
+            Error::SshTunnelService(s) => write!(f, "SSH tunnel service error: {}", s),
+            Error::ConfigurationParsing(e) => write!(f, "invalid configuration: {}", e),
+            Error::QmiResponseParsing(s) => write!(f, "invalid uqmi response: {}", s),
+            Error::SenderNotAllowed(s) => write!(f, "sender not allowed: {}", s),
+            Error::InvalidRequest(s) => write!(f, "invalid request: {}", s),
+            Error::DomainNameResolution => write!(f, "domain name resolution failed"),
+            Error::Ping(e) => write!(f, "ping failed: {}", e),
+            Error::InvalidStatus(s) => write!(f, "invalid status: {}", s),
+            Error::AtCommand(e) => write!(f, "modem error: {}", e),
+            Error::Ussd(s) => write!(f, "USSD error: {}", s),
+            Error::Authentication(s) => write!(f, "authentication failed: {}", s),
+            Error::CommandNotAllowed(s) => write!(f, "command not allowed: {}", s),
+            Error::RateLimitExceeded(s) => write!(f, "rate limit exceeded{}", s.as_ref().map(|s| format!(": {}", s)).unwrap_or_default()),
+        }
+    }
 }
 
 impl From<io::Error> for Error {
     fn from(value: io::Error) -> Self {
-        IoError(value)
+        Io(value)
+    }
+}
+
+impl From<AtError> for Error {
+    fn from(value: AtError) -> Self {
+        AtCommand(value)
     }
 }
 
 impl From<SurgeError> for Error {
     fn from(value: SurgeError) -> Self {
-        PingError(value)
+        Ping(value)
     }
 }
 
@@ -53,6 +112,20 @@ pub struct Context {
     pub configuration: Configuration,
     pub status: Status,
     pub tunnels: HashMap<u32, Tunnel>,
+    pub concatenated_sms_buffer: ConcatenatedSmsBuffer,
+    pub received_sms_cache: ReceivedSmsCache,
+    pub pending_deliveries: Vec<PendingDelivery>,
+    pub modem: Box<dyn Modem>,
+    pub unsolicited_results: UnboundedReceiver<UnsolicitedResult>,
+    pub outbox: Outbox,
+    pub last_balance_check: Option<SystemTime>,
+    pub rate_limiter: RateLimiter,
+    pub audit_log: AuditLog,
+    pub unknown_senders: UnknownSenderDigest,
+    pub pending_actions: PendingActions,
+    pub guests: GuestRegister,
+    //a totp code being accepted only once
+    pub totp_steps: TotpSteps,
 }
 
 
@@ -61,11 +134,26 @@ pub struct Tunnel {
     pub application: String,
     pub process: Child,
     pub creation_date: SystemTime,
+    pub max_duration: Duration,
 }
 
 impl Tunnel {
-    pub fn new(user: String, application: String, process: Child) -> Self {
-        Self { user, application, process, creation_date: SystemTime::now() }
+    pub fn new(user: String, application: String, process: Child, max_duration: Duration) -> Self {
+        Self { user, application, process, creation_date: SystemTime::now(), max_duration }
+    }
+}
+
+///Sent sms whose status reports are awaited
+pub struct PendingDelivery {
+    pub sms: OutgoingSms,
+    pub message_references: Vec<u8>,
+    pub attempts: u32,
+    pub sending_date: SystemTime,
+}
+
+impl PendingDelivery {
+    pub fn new(sms: OutgoingSms, message_references: Vec<u8>, attempts: u32) -> Self {
+        Self { sms, message_references, attempts, sending_date: SystemTime::now() }
     }
 }
 
@@ -80,14 +168,35 @@ pub struct Configuration {
     pub email_config: EmailConfig,
     pub ssh_config: SshConfig,
     pub init_config: InitConfig,
+    pub ussd_config: Option<UssdConfig>,
+    pub rate_limit_config: Option<RateLimitConfig>,
+    pub unknown_sender_config: Option<UnknownSenderConfig>,
+    pub confirmation_config: Option<ConfirmationConfig>,
 }
 
 impl Context {
-    pub fn new(configuration: Configuration, status: Status) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(mut configuration: Configuration, status: Status, modem: Box<dyn Modem>, unsolicited_results: UnboundedReceiver<UnsolicitedResult>, outbox: Outbox, audit_log: AuditLog, mut guests: GuestRegister, totp_steps: TotpSteps) -> Self {
+        //guests invited before a restart are users again until their invitation expires
+        guests.take_expired(SystemTime::now());
+        configuration.users.extend(guests.guests.iter().map(|guest| guest.user()));
         Self {
             configuration,
             status,
             tunnels: HashMap::new(),
+            concatenated_sms_buffer: ConcatenatedSmsBuffer::default(),
+            received_sms_cache: ReceivedSmsCache::default(),
+            pending_deliveries: Vec::new(),
+            modem,
+            unsolicited_results,
+            outbox,
+            last_balance_check: None,
+            rate_limiter: RateLimiter::default(),
+            audit_log,
+            unknown_senders: UnknownSenderDigest::default(),
+            pending_actions: PendingActions::default(),
+            guests,
+            totp_steps,
         }
     }
 
@@ -99,11 +208,11 @@ impl Context {
     pub async fn clean_up_expired_tunnels(&mut self) {
         debug!("clean_up_expired_tunnels: start");
         let current_time = SystemTime::now();
-        let max_duration = Duration::from_secs(self.configuration.ssh_config.tunnel_timeout_sec);
 
         let mut id_to_remove = Vec::new();
+        let mut notifications = Vec::new();
         for (id, tunnel) in &mut self.tunnels {
-            if current_time.duration_since(tunnel.creation_date).unwrap() > max_duration {
+            if current_time.duration_since(tunnel.creation_date).unwrap() > tunnel.max_duration {
                 info!("clean_up_expired_tunnels: tunnel: {} has expired",id);
 
                 //killing process
@@ -114,23 +223,295 @@ impl Context {
                 //notifying user
                 if let Some(user) = self.configuration.users.iter().find(|u| { u.name == tunnel.user }) {
                     debug!("clean_up_expired_tunnels - notifying user: {} about expiration",user.name);
-                    sms_utils::send_sms(&self.configuration.sms_config, &OutgoingSms {
+                    notifications.push(OutgoingSms {
                         to: user.phone_number.to_string(),
                         msg: format!("Expired tunnel {} has been closed", id),
-                    }).await.unwrap_or_else(|e| {
-                        error!("clean_up_expired_tunnels - cannot notify user - error : {:?}",e);
-                    })
+                    });
                 } else {
                     error!("clean_up_expired_tunnels: user {} not found",tunnel.user);
                 }
 
                 //retuning tunnel id to remove from map
-                id_to_remove.push(id.clone());
+                id_to_remove.push(*id);
             }
         }
         id_to_remove.iter().for_each(|id| {
             let _ = self.tunnels.remove(id);
         });
+        for notification in notifications {
+            self.send_sms(notification).await.unwrap_or_else(|e| {
+                error!("clean_up_expired_tunnels - cannot notify user - error : {:?}",e);
+            })
+        }
         debug!("clean_up_expired_tunnels: done");
     }
+
+    ///Registers the guest as a user, replacing any previous invitation of the same phone number
+    pub fn add_guest(&mut self, guest: Guest) {
+        info!("add_guest: {} invited to {} by {}",guest.phone_number,guest.application,guest.invited_by);
+        let name = guest.name();
+        self.configuration.users.retain(|user| user.name != name);
+        self.configuration.users.push(guest.user());
+        self.guests.add(guest);
+    }
+
+    ///Removes the guest user and closes its tunnels, returns None if the name is not a guest one
+    pub async fn revoke_guest(&mut self, name: &str) -> Option<Guest> {
+        let guest = self.guests.remove(name)?;
+        info!("revoke_guest: access of {} revoked",guest.phone_number);
+        self.remove_guest_user(&guest).await;
+        Some(guest)
+    }
+
+    ///Removes the guests whose invitation has expired, notifying them
+    pub async fn clean_up_expired_guests(&mut self) {
+        for guest in self.guests.take_expired(SystemTime::now()) {
+            info!("clean_up_expired_guests: invitation of {} has expired",guest.phone_number);
+            self.remove_guest_user(&guest).await;
+            let notification = OutgoingSms {
+                to: guest.phone_number.clone(),
+                msg: format!("Your access to {} has expired", guest.application),
+            };
+            self.send_sms(notification).await.unwrap_or_else(|e| {
+                error!("clean_up_expired_guests - cannot notify guest - error : {:?}",e);
+            })
+        }
+    }
+
+    async fn remove_guest_user(&mut self, guest: &Guest) {
+        let name = guest.name();
+        self.configuration.users.retain(|user| user.name != name);
+        let references: Vec<u32> = self.tunnels.iter().filter(|(_, tunnel)| tunnel.user == name).map(|(reference, _)| *reference).collect();
+        for reference in references {
+            if let Some(mut tunnel) = self.tunnels.remove(&reference) {
+                if let Err(e) = tunnel.process.kill().await {
+                    error!("remove_guest_user: cannot kill process - error: {:?}",e);
+                }
+                info!("remove_guest_user: tunnel {} of {} closed",reference,name);
+            }
+        }
+    }
+
+    ///Sends sms, keeping track of it until its delivery is reported if status reports are requested,
+    /// sms that cannot be sent are queued in the outbox to be sent again later
+    pub async fn send_sms(&mut self, sms: OutgoingSms) -> Result<()> {
+        self.send_tracked_sms(sms.clone(), 1).await.map_err(|e| {
+            error!("send_sms: cannot send sms to {}, queuing it in outbox - error: {:?}",sms.to,e);
+            self.outbox.push(sms);
+            e
+        })
+    }
+
+    ///Applies again the modem configuration if it has been lost, returns whether it had to be applied
+    pub async fn check_modem(&mut self) -> bool {
+        match sms_utils::check_configuration(self.modem.as_ref(), &self.configuration.sms_config).await {
+            Ok(true) => return false,
+            Ok(false) => error!("check_modem: modem configuration lost, modem probably restarted"),
+            Err(e) => error!("check_modem: cannot check modem configuration - error: {:?}",e),
+        }
+        match sms_utils::init(self.modem.as_ref(), &self.configuration.sms_config).await {
+            Ok(()) => info!("check_modem: modem configuration recovered"),
+            Err(e) => error!("check_modem: cannot recover modem configuration, retrying later - error: {:?}",e),
+        }
+        true
+    }
+
+    ///Sends the message by sms to each admin
+    pub async fn alert_admins(&mut self, msg: &str) {
+        let admins: Vec<String> = self.configuration.users.iter().filter(|user| user.is_admin()).map(|user| user.phone_number.clone()).collect();
+        if admins.is_empty() {
+            error!("alert_admins: no admin configured, alert not sent: {}",msg);
+        }
+        for admin in admins {
+            if let Err(e) = self.send_sms(OutgoingSms { to: admin, msg: msg.to_string() }).await {
+                error!("alert_admins: cannot alert admin - error: {:?}",e);
+            }
+        }
+    }
+
+    ///Checks the sim balance once the check period has elapsed, alerting admins if it is below the configured threshold
+    pub async fn check_balance(&mut self) {
+        let Some(ussd_config) = self.configuration.ussd_config.clone() else {
+            return;
+        };
+        let period = Duration::from_secs(ussd_config.balance_check_period_sec.unwrap_or(ussd_utils::DEFAULT_BALANCE_CHECK_PERIOD_SEC));
+        if self.last_balance_check.and_then(|date| date.elapsed().ok()).map(|elapsed| elapsed < period).unwrap_or(false) {
+            return;
+        }
+        let (Some(regex), Some(threshold)) = (ussd_config.balance_regex.as_deref(), ussd_config.balance_alert_threshold) else {
+            debug!("check_balance: no balance regex or alert threshold configured");
+            return;
+        };
+        self.last_balance_check = Some(SystemTime::now());
+        let balance = match ussd_utils::send_ussd(self.modem.as_ref(), &ussd_config.balance_code).await {
+            Ok(response) => match ussd_utils::read_balance(&response, regex) {
+                Ok(balance) => balance,
+                Err(e) => {
+                    error!("check_balance: cannot read balance - error: {:?}",e);
+                    return;
+                }
+            },
+            Err(e) => {
+                error!("check_balance: cannot query balance - error: {:?}",e);
+                return;
+            }
+        };
+        info!("check_balance: balance: {}",balance);
+        if balance < threshold {
+            error!("check_balance: balance {} below threshold {}",balance,threshold);
+            self.alert_admins(&format!("SIM balance is low: {} (alert threshold: {})", balance, threshold)).await;
+        }
+    }
+
+    ///Records a request or a call from a number not belonging to any user, if unknown senders are reported
+    pub fn record_unknown_sender(&mut self, sender: &str, message: &str) {
+        if let Some(config) = &self.configuration.unknown_sender_config {
+            self.unknown_senders.record(config, sender, message);
+        }
+    }
+
+    ///Sends admins the digest of the unknown senders once the digest period has elapsed
+    pub async fn report_unknown_senders(&mut self) {
+        let Some(config) = self.configuration.unknown_sender_config.clone() else {
+            return;
+        };
+        let Some(digest) = self.unknown_senders.take_digest(&config, SystemTime::now()) else {
+            return;
+        };
+        info!("report_unknown_senders: {}",digest);
+        match config.digest_channel.unwrap_or_default() {
+            DigestChannel::Sms => self.alert_admins(&digest).await,
+            DigestChannel::Email => {
+                for user in self.configuration.users.iter().filter(|user| user.is_admin()) {
+                    let email = OutgoingEmail {
+                        to: user.email.clone(),
+                        title: "Requests from unknown numbers".to_string(),
+                        msg: digest.clone(),
+                    };
+                    if let Err(e) = email_utils::send_email(&self.configuration.email_config, &email).await {
+                        error!("report_unknown_senders: cannot send digest to {} - error: {:?}",user.email,e);
+                    }
+                }
+            }
+        }
+    }
+
+    ///Sends admins the summary of the requests rejected by the rate limiter since the previous one
+    pub async fn report_rate_limiting(&mut self) {
+        if let Some(summary) = self.rate_limiter.take_summary() {
+            info!("report_rate_limiting: {}",summary);
+            self.alert_admins(&summary).await;
+        }
+    }
+
+    ///Sends again the outbox sms whose retry delay has elapsed
+    pub async fn retry_outbox(&mut self) {
+        let entries = self.outbox.take_due(SystemTime::now());
+        if entries.is_empty() {
+            return;
+        }
+        let max_retry = self.configuration.sms_config.sms_outbox_max_retry.unwrap_or(DEFAULT_SMS_OUTBOX_MAX_RETRY);
+        for entry in entries {
+            info!("retry_outbox: sending again sms to {} - attempt: {}",entry.to,entry.attempts + 1);
+            match self.send_tracked_sms(entry.sms(), 1).await {
+                Ok(()) => {
+                    info!("retry_outbox: sms to {} sent",entry.to);
+                }
+                Err(e) => {
+                    error!("retry_outbox: cannot send sms to {} - error: {:?}",entry.to,e);
+                    let to = entry.to.clone();
+                    if !self.outbox.reschedule(entry, max_retry, SystemTime::now()) {
+                        error!("retry_outbox: too many attempts, dropping sms to {}",to);
+                    }
+                }
+            }
+        }
+        self.outbox.save();
+    }
+
+    async fn send_tracked_sms(&mut self, sms: OutgoingSms, attempts: u32) -> Result<()> {
+        let message_references = sms_utils::send_sms(self.modem.as_ref(), &self.configuration.sms_config, &sms).await?;
+        if !self.configuration.sms_config.sms_status_report.unwrap_or(false) {
+            return Ok(());
+        }
+        match message_references {
+            Some(message_references) => self.pending_deliveries.push(PendingDelivery::new(sms, message_references, attempts)),
+            None => info!("send_tracked_sms: message references unknown, delivery to {} not tracked",sms.to),
+        }
+        Ok(())
+    }
+
+    pub async fn handle_status_report(&mut self, report: SmsStatusReport) {
+        debug!("handle_status_report: start");
+        let default_country_code = self.configuration.sms_config.default_country_code.as_deref();
+        let recipient = sms_utils::normalize_phone_number(&report.recipient_address.value, default_country_code);
+        let Some(index) = self.pending_deliveries.iter().position(|delivery| {
+            delivery.message_references.contains(&report.message_reference)
+                && sms_utils::normalize_phone_number(&delivery.sms.to, default_country_code) == recipient
+        }) else {
+            info!("handle_status_report: no pending delivery for message reference {}",report.message_reference);
+            return;
+        };
+        match report.delivery_status() {
+            DeliveryStatus::Delivered => {
+                let delivery = &mut self.pending_deliveries[index];
+                delivery.message_references.retain(|reference| *reference != report.message_reference);
+                if delivery.message_references.is_empty() {
+                    info!("handle_status_report: sms delivered to {} at {}",delivery.sms.to,report.discharge_time);
+                    let _ = self.pending_deliveries.remove(index);
+                }
+            }
+            DeliveryStatus::Pending => {
+                info!("handle_status_report: sms to {} not yet delivered, service centre still trying - status: {:02X?}",recipient,report.status);
+            }
+            DeliveryStatus::Failed => {
+                let delivery = self.pending_deliveries.remove(index);
+                error!("handle_status_report: sms delivery to {} failed - status: {:02X?} - attempt: {}",delivery.sms.to,report.status,delivery.attempts);
+                if delivery.attempts <= self.configuration.sms_config.sms_delivery_max_retry.unwrap_or(DEFAULT_SMS_DELIVERY_MAX_RETRY) {
+                    info!("handle_status_report: retrying sms delivery to {}",delivery.sms.to);
+                    let sms = delivery.sms.clone();
+                    if let Err(e) = self.send_tracked_sms(sms, delivery.attempts + 1).await {
+                        error!("handle_status_report: cannot send sms again - error: {:?}",e);
+                        self.escalate_delivery_failure(&delivery.sms).await;
+                    }
+                } else {
+                    self.escalate_delivery_failure(&delivery.sms).await;
+                }
+            }
+        }
+        debug!("handle_status_report: done");
+    }
+
+    ///Sends undelivered sms content by email to the user it was sent to
+    async fn escalate_delivery_failure(&self, sms: &OutgoingSms) {
+        let default_country_code = self.configuration.sms_config.default_country_code.as_deref();
+        let recipient = sms_utils::normalize_phone_number(&sms.to, default_country_code);
+        let Some(user) = self.configuration.users.iter().find(|user| {
+            sms_utils::normalize_phone_number(&user.phone_number, default_country_code) == recipient
+        }) else {
+            error!("escalate_delivery_failure: no user with phone number {}, sms is lost",sms.to);
+            return;
+        };
+        info!("escalate_delivery_failure: sending undelivered sms by email to {}",user.name);
+        email_utils::send_email(&self.configuration.email_config, &OutgoingEmail {
+            to: user.email.clone(),
+            title: "Undelivered SMS".to_string(),
+            msg: format!("Hello {} !\nThe following SMS could not be delivered to your phone:\n\n{}\n\nHave a nice day!", user.name, sms.msg),
+        }).await.unwrap_or_else(|e| {
+            error!("escalate_delivery_failure: cannot send email - error: {:?}",e);
+        });
+    }
+
+    ///Forgets sent sms whose status reports never came
+    pub fn clean_up_pending_deliveries(&mut self) {
+        let current_time = SystemTime::now();
+        let max_duration = Duration::from_secs(PENDING_DELIVERY_TIMEOUT_SEC);
+        self.pending_deliveries.retain(|delivery| {
+            let expired = current_time.duration_since(delivery.sending_date).unwrap_or_default() > max_duration;
+            if expired {
+                error!("clean_up_pending_deliveries: no status report received for sms sent to {}",delivery.sms.to);
+            }
+            !expired
+        });
+    }
 }
\ No newline at end of file
@@ -0,0 +1,133 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use crate::common;
+use crate::common::Error::InvalidRequest;
+
+pub const DEFAULT_CONFIRMATION_COMMANDS: [&str; 2] = ["reboot", "shutdown"];
+pub const DEFAULT_CONFIRMATION_WINDOW_SEC: u64 = 120;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct ConfirmationConfig {
+    //commands to be confirmed, defaults to reboot and shutdown
+    pub commands: Option<Vec<String>>,
+    pub window_sec: Option<u64>,
+}
+
+impl ConfirmationConfig {
+    pub fn is_confirmation_required(&self, command: &str) -> bool {
+        match &self.commands {
+            Some(commands) => commands.iter().any(|c| c.eq_ignore_ascii_case(command)),
+            None => DEFAULT_CONFIRMATION_COMMANDS.iter().any(|c| c.eq_ignore_ascii_case(command)),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_sec.unwrap_or(DEFAULT_CONFIRMATION_WINDOW_SEC))
+    }
+}
+
+struct PendingAction {
+    request: String,
+    code: String,
+    expiration_date: SystemTime,
+}
+
+///Requests awaiting their confirmation, at most one per user
+#[derive(Default)]
+pub struct PendingActions {
+    actions: HashMap<String, PendingAction>,
+}
+
+impl PendingActions {
+    ///Stores the request, replacing any previous one of the user, and returns its confirmation code
+    pub fn add(&mut self, config: &ConfirmationConfig, user: &str, request: &str, now: SystemTime) -> String {
+        let code = generate_code();
+        info!("add - request {:?} of {} awaiting confirmation",request,user);
+        self.actions.insert(user.to_string(), PendingAction {
+            request: request.to_string(),
+            code: code.clone(),
+            expiration_date: now + config.window(),
+        });
+        code
+    }
+
+    ///Returns the request confirmed by the code, the pending request being cancelled if the code is invalid or expired
+    pub fn confirm(&mut self, user: &str, code: Option<&str>, now: SystemTime) -> common::Result<String> {
+        let action = self.actions.remove(user).ok_or_else(|| {
+            error!("confirm - no request of {} awaiting confirmation",user);
+            InvalidRequest("No request to confirm".to_string())
+        })?;
+        if action.expiration_date < now {
+            error!("confirm - request {:?} of {} has expired",action.request,user);
+            return Err(InvalidRequest(format!("Confirmation of {} expired, request cancelled", action.request)));
+        }
+        if code != Some(action.code.as_str()) {
+            error!("confirm - invalid confirmation code from {}",user);
+            return Err(InvalidRequest(format!("Invalid confirmation code, {} cancelled", action.request)));
+        }
+        info!("confirm - request {:?} of {} confirmed",action.request,user);
+        Ok(action.request)
+    }
+}
+
+fn generate_code() -> String {
+    //std random hasher keys, the current time being hashed so that successive codes differ
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0));
+    format!("{:04}", hasher.finish() % 10000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confirmation_commands() {
+        let config = ConfirmationConfig::default();
+        assert!(config.is_confirmation_required("Reboot"));
+        assert!(!config.is_confirmation_required("close"));
+        let config = ConfirmationConfig { commands: Some(vec!("close".to_string())), window_sec: None };
+        assert!(config.is_confirmation_required("close"));
+        assert!(!config.is_confirmation_required("reboot"));
+    }
+
+    #[test]
+    fn request_is_confirmed_once() {
+        let config = ConfirmationConfig::default();
+        let mut pending_actions = PendingActions::default();
+        let now = SystemTime::now();
+        let code = pending_actions.add(&config, "alice", "reboot", now);
+        assert_eq!(code.len(), 4);
+        assert!(pending_actions.confirm("bob", Some(&code), now).is_err());
+        assert_eq!(pending_actions.confirm("alice", Some(&code), now).unwrap(), "reboot");
+        assert!(pending_actions.confirm("alice", Some(&code), now).is_err());
+    }
+
+    #[test]
+    fn request_is_cancelled_by_invalid_or_late_confirmation() {
+        let config = ConfirmationConfig { commands: None, window_sec: Some(60) };
+        let mut pending_actions = PendingActions::default();
+        let now = SystemTime::now();
+        let code = pending_actions.add(&config, "alice", "reboot", now);
+        assert!(pending_actions.confirm("alice", None, now).is_err());
+        assert!(pending_actions.confirm("alice", Some(&code), now).is_err());
+
+        let code = pending_actions.add(&config, "alice", "shutdown", now);
+        assert!(pending_actions.confirm("alice", Some(&code), now + Duration::from_secs(61)).is_err());
+        assert!(pending_actions.confirm("alice", Some(&code), now).is_err());
+    }
+
+    #[test]
+    fn new_request_replaces_pending_one() {
+        let config = ConfirmationConfig::default();
+        let mut pending_actions = PendingActions::default();
+        let now = SystemTime::now();
+        let _ = pending_actions.add(&config, "alice", "reboot", now);
+        let code = pending_actions.add(&config, "alice", "shutdown", now);
+        assert_eq!(pending_actions.confirm("alice", Some(&code), now).unwrap(), "shutdown");
+    }
+}
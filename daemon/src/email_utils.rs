@@ -41,10 +41,10 @@ pub async fn send_email(config: &EmailConfig, email: &OutgoingEmail) -> common::
                 .arg("-F")
                 .arg(&config.sender_alias)
                 .spawn()?;
-            let mut stdin = send_mail_process.stdin.take().ok_or(common::Error::SystemCommandExecutionError)?;
-            let mut stderr = send_mail_process.stderr.take().ok_or(common::Error::SystemCommandExecutionError)?;
+            let mut stdin = send_mail_process.stdin.take().ok_or(common::Error::SystemCommandExecution)?;
+            let mut stderr = send_mail_process.stderr.take().ok_or(common::Error::SystemCommandExecution)?;
             stdin
-                .write(content.as_bytes())
+                .write_all(content.as_bytes())
                 .await?;
             // We drop the handle here which signals EOF to the child process.
             // This tells the child process that it there is no more data on the pipe.
@@ -57,10 +57,10 @@ pub async fn send_email(config: &EmailConfig, email: &OutgoingEmail) -> common::
             let _ = stderr.read_to_string(&mut s).await?;
             debug!("send_email: command output :\n{}", s);
 
-            output.status.success().then(|| ()).ok_or(common::Error::SystemCommandExecutionError)
+            output.status.success().then_some(()).ok_or(common::Error::SystemCommandExecution)
         })
         .await.unwrap_or_else(|_| {
             error!("send_email: timeout while trying to send email");
-            Err(Error::SystemCommandExecutionError)
+            Err(Error::SystemCommandExecution)
         })
 }
\ No newline at end of file
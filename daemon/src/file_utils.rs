@@ -0,0 +1,36 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{debug, error};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+///Reads the content of a toml file, the default content is returned if the file does not exist or cannot be parsed
+pub fn load_toml<T: DeserializeOwned + Default>(path: &str) -> T {
+    let mut content = String::new();
+    match File::open(path).and_then(|mut file| file.read_to_string(&mut content)) {
+        Ok(_) => toml::from_str::<T>(&content).unwrap_or_else(|e| {
+            error!("load_toml: cannot parse file {} - error: {:?}",path,e);
+            T::default()
+        }),
+        Err(e) => {
+            debug!("load_toml: cannot read file {} - error: {:?}",path,e);
+            T::default()
+        }
+    }
+}
+
+///Writes the content to a toml file, replacing the previous one only once completely written,
+/// so that an interrupted writing does not lose the previous content
+pub fn save_toml<T: Serialize>(path: &str, content: &T) -> std::io::Result<()> {
+    let content = toml::to_string(content).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let temporary_path = format!("{}.tmp", path);
+    std::fs::OpenOptions::new().create(true).write(true).truncate(true).open(&temporary_path)?
+        .write_all(content.as_bytes())?;
+    std::fs::rename(&temporary_path, path)
+}
+
+///Seconds since unix epoch, as persisted dates
+pub fn unix_time(date: SystemTime) -> u64 {
+    date.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
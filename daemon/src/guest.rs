@@ -0,0 +1,171 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use crate::file_utils;
+use crate::file_utils::unix_time;
+use crate::user::User;
+
+const GUEST_COMMANDS: [&str; 3] = ["open", "close", "status"];
+
+///Users invited by an admin for a limited time, persisted so that they survive a restart
+pub struct GuestRegister {
+    pub path: String,
+    pub guests: Vec<Guest>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Guest {
+    pub phone_number: String,
+    pub email: String,
+    pub application: String,
+    pub invited_by: String,
+    //seconds since unix epoch
+    pub expiration_date: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct GuestRegisterContent {
+    #[serde(default)]
+    guest: Vec<Guest>,
+}
+
+impl Guest {
+    ///`phone_number` is expected normalized, identifying the guest
+    pub fn new(phone_number: &str, email: &str, application: &str, invited_by: &str, duration: Duration) -> Self {
+        Guest {
+            phone_number: phone_number.to_string(),
+            email: email.to_string(),
+            application: application.to_string(),
+            invited_by: invited_by.to_string(),
+            expiration_date: unix_time(SystemTime::now() + duration),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        format!("guest-{}", self.phone_number)
+    }
+
+    pub fn expiration_date(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.expiration_date)
+    }
+
+    pub fn is_expired(&self, date: SystemTime) -> bool {
+        self.expiration_date <= unix_time(date)
+    }
+
+    ///User restricted to the application the guest is invited to, whose tunnels do not outlive the invitation
+    pub fn user(&self) -> User {
+        User {
+            name: self.name(),
+            phone_number: self.phone_number.clone(),
+            email: self.email.clone(),
+            call: None,
+            admin: None,
+            pin: None,
+            totp_secret: None,
+            code_required_commands: None,
+            role: None,
+            allowed_commands: Some(GUEST_COMMANDS.iter().map(|c| c.to_string()).collect()),
+            applications: Some(vec!(self.application.clone())),
+            tunnel_max_duration_sec: Some(self.expiration_date.saturating_sub(unix_time(SystemTime::now()))),
+        }
+    }
+}
+
+impl GuestRegister {
+    ///Reads the guests of the register file, the register is empty if the file does not exist
+    pub fn load(path: &str) -> Self {
+        let guests = file_utils::load_toml::<GuestRegisterContent>(path).guest;
+        info!("load: {} guests",guests.len());
+        GuestRegister { path: path.to_string(), guests }
+    }
+
+    ///Adds the guest, replacing any previous invitation of the same phone number
+    pub fn add(&mut self, guest: Guest) {
+        self.guests.retain(|g| g.phone_number != guest.phone_number);
+        self.guests.push(guest);
+        self.save();
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Guest> {
+        let index = self.guests.iter().position(|guest| guest.name() == name)?;
+        let guest = self.guests.remove(index);
+        self.save();
+        Some(guest)
+    }
+
+    ///Removes and returns the guests whose invitation has expired
+    pub fn take_expired(&mut self, date: SystemTime) -> Vec<Guest> {
+        let (expired, active): (Vec<Guest>, Vec<Guest>) = std::mem::take(&mut self.guests).into_iter().partition(|guest| guest.is_expired(date));
+        self.guests = active;
+        if !expired.is_empty() {
+            self.save();
+        }
+        expired
+    }
+
+    pub fn save(&self) {
+        let content = GuestRegisterContent { guest: self.guests.clone() };
+        let result = file_utils::save_toml(&self.path, &content);
+        match result {
+            Ok(()) => debug!("save: {} guests",self.guests.len()),
+            Err(e) => error!("save: cannot write guest file {} - error: {:?}",self.path,e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(name: &str) -> GuestRegister {
+        let path = std::env::temp_dir().join(format!("telco-vecchio-guests-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        GuestRegister::load(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn guest_user_is_restricted_to_its_application() {
+        let guest = Guest::new("+33611111111", "bob@example.com", "camera", "alice", Duration::from_secs(3600));
+        let user = guest.user();
+        assert_eq!(user.name, "guest-+33611111111");
+        assert!(user.is_command_allowed("close"));
+        assert!(!user.is_command_allowed("invite"));
+        assert!(user.is_application_allowed("camera"));
+        assert!(!user.is_application_allowed("nas"));
+        assert!(!user.is_admin());
+        assert!(user.tunnel_max_duration_sec.unwrap() <= 3600);
+    }
+
+    #[test]
+    fn expired_guests_are_taken() {
+        let mut register = register("expiry");
+        let now = SystemTime::now();
+        register.add(Guest::new("+33611111111", "bob@example.com", "camera", "alice", Duration::from_secs(60)));
+        register.add(Guest::new("+33622222222", "carol@example.com", "nas", "alice", Duration::from_secs(3600)));
+        assert!(register.take_expired(now).is_empty());
+        let expired = register.take_expired(now + Duration::from_secs(120));
+        assert_eq!(expired.iter().map(|guest| guest.phone_number.as_str()).collect::<Vec<&str>>(), vec!("+33611111111"));
+        assert_eq!(GuestRegister::load(&register.path).guests, register.guests);
+        assert_eq!(register.guests.len(), 1);
+        let _ = std::fs::remove_file(&register.path);
+    }
+
+    #[test]
+    fn guests_are_reloaded() {
+        let mut register = register("persistence");
+        register.add(Guest::new("+33611111111", "bob@example.com", "camera", "alice", Duration::from_secs(60)));
+        register.add(Guest::new("+33622222222", "carol@example.com", "nas", "alice", Duration::from_secs(3600)));
+        //a new invitation replaces the previous one
+        register.add(Guest::new("+33611111111", "bob@example.com", "nas", "alice", Duration::from_secs(600)));
+        let reloaded = GuestRegister::load(&register.path);
+        assert_eq!(reloaded.guests, register.guests);
+        assert_eq!(reloaded.guests.len(), 2);
+        assert_eq!(reloaded.guests[1].application, "nas");
+
+        assert!(register.remove("guest-+33611111111").is_some());
+        assert!(register.remove("guest-+33611111111").is_none());
+        assert_eq!(GuestRegister::load(&register.path).guests.len(), 1);
+        let _ = std::fs::remove_file(&register.path);
+    }
+}
@@ -6,10 +6,16 @@ use fern::Output;
 use log::{debug, error, info};
 use rolling_file::{BasicRollingFileAppender, RollingConditionBasic};
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
 use crate::{common, sms_utils, status};
+use crate::audit::AuditLog;
+use crate::auth::TotpSteps;
 use crate::common::{Configuration, Context};
-use crate::common::Error::ConfigurationParsingError;
-use crate::sms_utils::OutgoingSms;
+use crate::common::Error::{ConfigurationParsing, SmsInit};
+use crate::guest::GuestRegister;
+use crate::modem::{Modem, SerialModem, UnavailableModem};
+use crate::outbox::Outbox;
+use crate::sms_utils::{OutgoingSms, SmsBackend};
 use crate::status::{DeviceStatus, QmiProvider};
 use crate::user::User;
 
@@ -18,8 +24,13 @@ const SHARE_DIRECTORY: &str = "/usr/share/telco-vecchio";
 const LOG_DIRECTORY: &str = "/tmp/log/telco-vecchio";
 const LOG_FILE: &str = "log";
 const INIT_LISTENER_REGISTER: &str = "init-listener-register";
+const OUTBOX_FILE: &str = "outbox";
+const AUDIT_FILE: &str = "audit";
+const GUEST_FILE: &str = "guests";
+const TOTP_STEP_FILE: &str = "totp-steps";
 
 const LOG_FILE_MAX_SIZE: u64 = 10000;
+const AUDIT_FILE_MAX_SIZE: u64 = 100000;
 const MAX_LOG_FILES: usize = 2;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -88,7 +99,7 @@ pub async fn init(is_daemon : bool) -> common::Result<Context> {
                 //wait for a while and retry
                 if sleep_loop_counter > 0 {
                     info!("init - device not yet connected to network, retrying after {} seconds",configuration.init_config.init_status_refresh_period_seconds);
-                    sleep_loop_counter = sleep_loop_counter - 1;
+                    sleep_loop_counter -= 1;
                     tokio::time::sleep(Duration::from_secs(configuration.init_config.init_status_refresh_period_seconds)).await;
                     status = status::get_status(&configuration).await?;
                 } else {
@@ -103,23 +114,42 @@ pub async fn init(is_daemon : bool) -> common::Result<Context> {
         }
     };
 
-    if status.device_status != DeviceStatus::SimLocked && status.device_status != DeviceStatus::LteNotConnected {
+    let sms_available = status.device_status != DeviceStatus::SimLocked && status.device_status != DeviceStatus::LteNotConnected;
+    let (modem, unsolicited_results): (Box<dyn Modem>, _) = match configuration.sms_config.sms_backend.unwrap_or_default() {
+        SmsBackend::At => {
+            let (modem, unsolicited_results) = SerialModem::start(&configuration.sms_config).map_err(|_| SmsInit)?;
+            (Box::new(modem), unsolicited_results)
+        }
+        SmsBackend::Qmi => {
+            //serial port left to other programs, no unsolicited result is received
+            info!("init - sms handled through uqmi, modem serial port not used");
+            let (_, unsolicited_results) = mpsc::unbounded_channel();
+            (Box::new(UnavailableModem), unsolicited_results)
+        }
+    };
+    let outbox = Outbox::load(&outbox_path());
+    let mut context = Context::new(configuration, status, modem, unsolicited_results, outbox, audit_log(), GuestRegister::load(&guests_path()), TotpSteps::load(&totp_steps_path()));
+
+    if sms_available {
         //even if status is not ready, sms might be sent or received
-        sms_utils::init(&configuration.sms_config).await?;
+        sms_utils::init(context.modem.as_ref(), &context.configuration.sms_config).await?;
 
 
-        if let Some(init_listener) = lookup_init_listener(&configuration){
+        if let Some(init_listener) = lookup_init_listener(&context.configuration){
             info!("init - notifying registered init listener : {}",init_listener.name);
-            sms_utils::send_sms(&configuration.sms_config, &OutgoingSms {
+            let notification = OutgoingSms {
                 to: init_listener.phone_number.to_string(),
-                msg: format!("Telco-Vecchio is up.\n{}",status.to_string())
-            }).await.unwrap_or_else(|e|{
+                msg: format!("Telco-Vecchio is up.\n{}",context.status)
+            };
+            context.send_sms(notification).await.unwrap_or_else(|e|{
                 error!("init - cannot notify registered init listener - error : {:?}",e);
             })
         }
+
+        //sms left unsent before shutdown
+        context.retry_outbox().await;
     };
 
-    let context = Context::new(configuration, status);
     info!("init - initialization success");
     Ok(context)
 }
@@ -128,17 +158,33 @@ pub fn read_config_file(path : &str) -> common::Result<Configuration>{
     let mut configuration_string = String::new();
     File::open(path)?.read_to_string(&mut configuration_string)?;
     info!("init - configuration content:\n{}",configuration_string);
-    let config = toml::from_str(&configuration_string).map_err(|e| ConfigurationParsingError(e))?;
+    let config = toml::from_str(&configuration_string).map_err(ConfigurationParsing)?;
     Ok(config)
 }
 
 
+pub fn outbox_path() -> String {
+    format!("{}/{}", SHARE_DIRECTORY, OUTBOX_FILE)
+}
+
+pub fn guests_path() -> String {
+    format!("{}/{}", SHARE_DIRECTORY, GUEST_FILE)
+}
+
+pub fn totp_steps_path() -> String {
+    format!("{}/{}", SHARE_DIRECTORY, TOTP_STEP_FILE)
+}
+
+pub fn audit_log() -> AuditLog {
+    AuditLog::new(&format!("{}/{}", SHARE_DIRECTORY, AUDIT_FILE), AUDIT_FILE_MAX_SIZE)
+}
+
 pub fn register_init_listener(user: &User){
     let path = format!("{}/{}", SHARE_DIRECTORY, INIT_LISTENER_REGISTER);
     //erase any previous content in the file
     match std::fs::OpenOptions::new().create(true).write(true).truncate(true).open(Path::new(&path)) {
         Ok(mut file) => {
-            match file.write(user.name.as_bytes()) {
+            match file.write_all(user.name.as_bytes()) {
                 Ok(_) => {
                     debug!("register_init_listener - user {:?} registered as init listener", user.name)
                 }
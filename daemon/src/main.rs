@@ -1,24 +1,42 @@
+mod auth;
+mod audit;
 mod common;
+mod confirmation;
 mod sms_utils;
+mod pdu;
+mod modem;
+mod qmi_sms;
+mod outbox;
 mod email_utils;
+mod file_utils;
+mod guest;
 mod ssh_utils;
 mod user;
 mod application;
 mod request;
 mod init;
 mod status;
+mod ussd_utils;
+mod rate_limit;
+mod unknown_sender;
+#[cfg(test)]
+mod simulator;
 
 use std::env;
 use std::process::ExitCode;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use fork::{daemon, Fork};
 use log::{debug, error, info};
 use tokio::process::Command;
 use crate::common::{Context, Error};
 use crate::init::init;
-use crate::sms_utils::OutgoingSms;
+use crate::outbox::Outbox;
+use crate::request::RequestOrigin;
+use crate::sms_utils::{IncomingMessage, IncomingSms, OutgoingSms, SmsReceptionMode};
 use crate::status::QmiProvider;
 
+const DEFAULT_AUDIT_CLI_ENTRIES: usize = 50;
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> ExitCode {
     let args: Vec<String> = env::args().collect();
@@ -36,7 +54,7 @@ async fn main() -> ExitCode {
         }
         Some("--check-config") => {
             if let Some(path) = args.get(2) {
-                if let Ok(_) = init::read_config_file(path) {
+                if init::read_config_file(path).is_ok() {
                     println!("Valid configuration");
                     ExitCode::SUCCESS
                 } else {
@@ -48,6 +66,24 @@ async fn main() -> ExitCode {
                 ExitCode::FAILURE
             }
         }
+        Some("--outbox") => {
+            print!("{}", Outbox::load(&init::outbox_path()));
+            ExitCode::SUCCESS
+        }
+        Some("--audit") => {
+            match args.get(2).map(|count| count.parse::<usize>()).unwrap_or(Ok(DEFAULT_AUDIT_CLI_ENTRIES)) {
+                Ok(count) => {
+                    for entry in init::audit_log().latest(count) {
+                        println!("{}", entry);
+                    }
+                    ExitCode::SUCCESS
+                }
+                Err(_) => {
+                    println!("Invalid entry number");
+                    ExitCode::FAILURE
+                }
+            }
+        }
         _ => {
             println!("invalid input arguments");
             ExitCode::FAILURE
@@ -59,10 +95,12 @@ async fn run(is_daemon: bool) {
     let task = tokio::spawn(async move {
         match init(is_daemon).await {
             Ok(mut context) => {
+                //handling sms received while the daemon was not running
+                handle_sms_backlog(&mut context).await;
                 loop {
                     debug!("waiting for SMS....");
                     let tunnel_refresh_duration = Duration::from_secs(context.configuration.ssh_config.tunnel_refresh_period_sec);
-                    let wait_result = tokio::time::timeout(tunnel_refresh_duration, sms_utils::wait_sms(&context.configuration.sms_config)).await;
+                    let wait_result = tokio::time::timeout(tunnel_refresh_duration, sms_utils::wait_sms(context.modem.as_ref(), &mut context.unsolicited_results, &context.configuration.sms_config, &mut context.concatenated_sms_buffer)).await;
                     debug!("SMS waiting interrupted...");
                     match wait_result {
                         Err(_) => {
@@ -77,52 +115,51 @@ async fn run(is_daemon: bool) {
                             debug!("Internet ping ok");
 
 
+                            debug!("Modem check...");
+                            let modem_configured = context.check_modem().await;
+                            //handling sms stored while the modem was not configured, and in store reception mode
+                            // the ones whose reading was interrupted by the periodic routines, their +CMTI being lost
+                            if modem_configured || context.configuration.sms_config.sms_reception_mode.unwrap_or_default() == SmsReceptionMode::Store {
+                                handle_sms_backlog(&mut context).await;
+                            }
+
+                            debug!("Concatenated SMS expiry...");
+                            sms_utils::remove_expired_concatenated_sms(context.modem.as_ref(), &context.configuration.sms_config, &mut context.concatenated_sms_buffer).await;
+
+                            debug!("Guests refresh...");
+                            context.clean_up_expired_guests().await;
+
                             debug!("Tunnel refresh...");
                             context.clean_up_expired_tunnels().await;
                             debug!("Tunnels refreshing done");
 
+                            context.clean_up_pending_deliveries();
+
+                            debug!("Outbox retry...");
+                            context.retry_outbox().await;
+
+                            debug!("Balance check...");
+                            context.check_balance().await;
+
+                            debug!("Rate limiting summary...");
+                            context.report_rate_limiting().await;
+
+                            debug!("Unknown senders digest...");
+                            context.report_unknown_senders().await;
+
                             debug!("Periodic routines done");
                         }
                         Ok(sms_reception_result) => {
                             debug!("New SMS received");
                             match sms_reception_result {
-                                Ok(sms) => {
-                                    let response = match request::handle_request(sms.from.as_str(), sms.msg.as_str(), &mut context).await {
-                                        Ok(message) => {
-                                            Some(message)
-                                        }
-                                        Err(Error::SenderNotAllowed(_)) => {
-                                            //stay silent
-                                            None
-                                        }
-                                        Err(Error::InvalidRequestError(s)) => {
-                                            //applicative error
-                                            Some(format!("The message you sent is invalid, {}", s))
-                                        }
-                                        Err(Error::InvalidStatus(s)) => {
-                                            //applicative error
-                                            Some(format!("Your request cannot be processed, {}", s))
-                                        }
-                                        Err(e) => {
-                                            //technical error
-                                            Some(format!("An error occurred, {:?}", e))
-                                        }
-                                    };
-
-                                    if let Some(message) = response {
-                                        info!("Sending back response");
-                                        match sms_utils::send_sms(&context.configuration.sms_config,
-                                                                  &OutgoingSms { to: sms.from.to_string(), msg: message }).await {
-                                            Ok(()) => {
-                                                info!("Response sent");
-                                            }
-                                            Err(e) => {
-                                                error!("Error while sending back response: {:?}",e);
-                                            }
-                                        }
-                                    } else {
-                                        info!("No response to send back");
-                                    }
+                                Ok(IncomingMessage::StatusReport(report)) => {
+                                    context.handle_status_report(report).await;
+                                }
+                                Ok(IncomingMessage::Sms(sms)) => {
+                                    handle_sms(sms, &mut context).await;
+                                }
+                                Ok(IncomingMessage::Call(caller)) => {
+                                    handle_call(caller.as_str(), &mut context).await;
                                 }
                                 Err(e) => {
                                     error!("SMS listening failed {:?}, retrying",e);
@@ -140,6 +177,818 @@ async fn run(is_daemon: bool) {
     task.await.unwrap();
 }
 
+async fn handle_sms_backlog(context: &mut Context) {
+    match sms_utils::read_sms_backlog(context.modem.as_ref(), &context.configuration.sms_config, &mut context.concatenated_sms_buffer).await {
+        Ok(backlog) => {
+            for sms in backlog {
+                handle_sms(sms, context).await;
+            }
+        }
+        Err(e) => {
+            error!("Stored SMS reading failed {:?}",e);
+        }
+    }
+}
 
+async fn handle_sms(sms: IncomingSms, context: &mut Context) {
+    let max_age = Duration::from_secs(context.configuration.sms_config.sms_max_age_sec.unwrap_or(sms_utils::DEFAULT_SMS_MAX_AGE_SEC));
+    if context.received_sms_cache.is_duplicate(&sms, max_age) {
+        info!("Duplicate SMS from {} ignored",sms.from);
+        sms_utils::delete_sms(context.modem.as_ref(), &context.configuration.sms_config, &sms).await;
+        return;
+    }
+
+    let result = request::handle_request(sms.from.as_str(), sms.msg.as_str(), RequestOrigin::Sms, sms.sending_date, context).await;
+    send_response(sms.from.as_str(), result, context).await;
+
+    //sms handled, it can be removed from modem storage
+    sms_utils::delete_sms(context.modem.as_ref(), &context.configuration.sms_config, &sms).await;
+}
+
+///Runs the request configured for the caller, as if it had been sent by sms
+async fn handle_call(caller: &str, context: &mut Context) {
+    let call_request = match request::find_user(caller, &context.configuration) {
+        Ok(user) => user.call.clone(),
+        Err(_) => {
+            info!("Call from unknown number {:?} ignored",caller);
+            context.record_unknown_sender(caller, "(call)");
+            return;
+        }
+    };
+    let result = match call_request {
+        Some(call_request) => request::handle_request(caller, call_request.as_str(), RequestOrigin::Call, SystemTime::now(), context).await,
+        None => Err(Error::InvalidRequest("No request is configured for your calls".to_string())),
+    };
+    send_response(caller, result, context).await;
+}
+
+async fn send_response(to: &str, result: common::Result<String>, context: &mut Context) {
+    let response = match result {
+        Ok(message) => {
+            Some(message)
+        }
+        Err(Error::SenderNotAllowed(_)) => {
+            //stay silent
+            None
+        }
+        Err(Error::InvalidRequest(s)) => {
+            //applicative error
+            Some(format!("The message you sent is invalid, {}", s))
+        }
+        Err(Error::InvalidStatus(s)) => {
+            //applicative error
+            Some(format!("Your request cannot be processed, {}", s))
+        }
+        Err(Error::Authentication(s)) => {
+            //applicative error
+            Some(format!("Your request cannot be authenticated, {}", s))
+        }
+        Err(Error::RateLimitExceeded(s)) => {
+            //answering the first rejected request only
+            s.map(|s| format!("Your request is rejected, {}", s))
+        }
+        Err(Error::CommandNotAllowed(s)) => {
+            //applicative error
+            Some(format!("Your request is not allowed, {}", s))
+        }
+        Err(e) => {
+            //technical error
+            Some(format!("An error occurred, {}", e))
+        }
+    };
 
+    if let Some(message) = response {
+        info!("Sending back response");
+        match context.send_sms(OutgoingSms { to: to.to_string(), msg: message }).await {
+            Ok(()) => {
+                info!("Response sent");
+            }
+            Err(e) => {
+                error!("Error while sending back response: {:?}",e);
+            }
+        }
+    } else {
+        info!("No response to send back");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+    use crate::application::Application;
+    use crate::audit::AuditLog;
+    use crate::auth::TotpSteps;
+    use crate::common::Tunnel;
+    use crate::guest::{Guest, GuestRegister};
+    use crate::modem::{SerialModem, UnavailableModem};
+    use crate::simulator::{ModemSimulator, UqmiSimulator};
+    use crate::status::{DeviceStatus, ServiceStatus, Status};
+
+    const USER_PHONE_NUMBER: &str = "+33612345678";
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn configuration(modem_device: &str, sms_options: &str) -> common::Configuration {
+        toml::from_str(&format!(r#"
+            application = []
+
+            [[user]]
+            name = "alice"
+            phone_number = "{USER_PHONE_NUMBER}"
+            email = "alice@example.com"
+            call = "close"
+            admin = true
+
+            [sms_config]
+            modem_device = "{modem_device}"
+            qmi_modem_device = "/dev/cdc-wdm0"
+            qmi_binary_file = "uqmi"
+            sim_pin = "0000"
+            sms_send_timeout_sec = 5
+            {sms_options}
+
+            [email_config]
+            binary_file = "sendmail"
+            sender_alias = "Telco-Vecchio"
+            server_domain = "example.com"
+            internet_host = "8.8.8.8"
+            email_send_timeout_sec = 10
+
+            [ssh_config]
+            binary_file = "ssh"
+            key_file = "key"
+            service_user = "v2"
+            service_host = "example.com"
+            tunnel_input_port = 0
+            tunnel_setup_timeout_sec = 5
+            tunnel_timeout_sec = 3600
+            tunnel_refresh_period_sec = 60
+
+            [init_config]
+            init_status_refresh_period_seconds = 10
+            init_status_refresh_max_retry = 10
+
+            [ussd_config]
+            balance_code = "*100#"
+            balance_regex = "([0-9]+,[0-9]+) EUR"
+            balance_alert_threshold = 5.0
+        "#)).unwrap()
+    }
+
+    async fn start(simulator: &ModemSimulator, sms_options: &str) -> Context {
+        let configuration = configuration(&simulator.device, sms_options);
+        let (modem, unsolicited_results) = SerialModem::start(&configuration.sms_config).unwrap();
+        let status = Status {
+            device_status: DeviceStatus::Ready,
+            email_service_status: ServiceStatus::Reachable,
+            ssh_tunnel_service_status: ServiceStatus::Reachable,
+            applications_status: HashMap::new(),
+        };
+        let outbox_path = std::env::temp_dir().join(format!("telco-vecchio-outbox-{}", simulator.device.replace('/', "-")));
+        let _ = std::fs::remove_file(&outbox_path);
+        let outbox = Outbox::load(outbox_path.to_str().unwrap());
+        let audit_path = std::env::temp_dir().join(format!("telco-vecchio-audit-{}", simulator.device.replace('/', "-")));
+        let _ = std::fs::remove_file(&audit_path);
+        let audit_log = AuditLog::new(audit_path.to_str().unwrap(), 100000);
+        let guests_path = std::env::temp_dir().join(format!("telco-vecchio-guests-{}", simulator.device.replace('/', "-")));
+        let _ = std::fs::remove_file(&guests_path);
+        let guests = GuestRegister::load(guests_path.to_str().unwrap());
+        let totp_steps_path = std::env::temp_dir().join(format!("telco-vecchio-totp-steps-{}", simulator.device.replace('/', "-")));
+        let _ = std::fs::remove_file(&totp_steps_path);
+        let totp_steps = TotpSteps::load(totp_steps_path.to_str().unwrap());
+        let context = Context::new(configuration, status, Box::new(modem), unsolicited_results, outbox, audit_log, guests, totp_steps);
+        sms_utils::init(context.modem.as_ref(), &context.configuration.sms_config).await.unwrap();
+        context
+    }
+
+    async fn start_qmi(uqmi: &UqmiSimulator) -> Context {
+        let mut configuration = configuration("/dev/null", r#"sms_backend = "qmi""#);
+        configuration.sms_config.qmi_binary_file = uqmi.binary.clone();
+        configuration.sms_config.qmi_modem_device = uqmi.device.clone();
+        let status = Status {
+            device_status: DeviceStatus::Ready,
+            email_service_status: ServiceStatus::Reachable,
+            ssh_tunnel_service_status: ServiceStatus::Reachable,
+            applications_status: HashMap::new(),
+        };
+        let outbox = Outbox::load(std::path::Path::new(&uqmi.device).join("outbox").to_str().unwrap());
+        let (_, unsolicited_results) = tokio::sync::mpsc::unbounded_channel();
+        let audit_log = AuditLog::new(std::path::Path::new(&uqmi.device).join("audit").to_str().unwrap(), 100000);
+        let guests = GuestRegister::load(std::path::Path::new(&uqmi.device).join("guests").to_str().unwrap());
+        let totp_steps = TotpSteps::load(std::path::Path::new(&uqmi.device).join("totp-steps").to_str().unwrap());
+        let context = Context::new(configuration, status, Box::new(UnavailableModem), unsolicited_results, outbox, audit_log, guests, totp_steps);
+        sms_utils::init(context.modem.as_ref(), &context.configuration.sms_config).await.unwrap();
+        context
+    }
+
+    async fn handle_next_call(context: &mut Context) {
+        let message = tokio::time::timeout(TIMEOUT, sms_utils::wait_sms(context.modem.as_ref(), &mut context.unsolicited_results, &context.configuration.sms_config, &mut context.concatenated_sms_buffer)).await
+            .unwrap()
+            .unwrap();
+        let IncomingMessage::Call(caller) = message else {
+            panic!("call expected, got {:?}", message);
+        };
+        handle_call(caller.as_str(), context).await;
+    }
+
+    async fn handle_next_sms(context: &mut Context) {
+        let message = tokio::time::timeout(TIMEOUT, sms_utils::wait_sms(context.modem.as_ref(), &mut context.unsolicited_results, &context.configuration.sms_config, &mut context.concatenated_sms_buffer)).await
+            .unwrap()
+            .unwrap();
+        let IncomingMessage::Sms(sms) = message else {
+            panic!("sms expected, got {:?}", message);
+        };
+        handle_sms(sms, context).await;
+    }
+
+    ///Sends the requests from the number, each one being handled before the next one is sent
+    async fn send_requests(simulator: &ModemSimulator, context: &mut Context, from: &str, requests: &[&str]) {
+        for request in requests {
+            simulator.inject_sms(from, request);
+            handle_next_sms(context).await;
+        }
+    }
+
+    ///Process standing for the ssh one of a tunnel
+    fn sleeping_process() -> tokio::process::Child {
+        tokio::process::Command::new("sleep").arg("60").kill_on_drop(true).spawn().unwrap()
+    }
+
+    ///Checks the messages sent to the user, in sending order
+    fn assert_responses(simulator: &ModemSimulator, expected: &[&str]) {
+        let expected: Vec<(String, String)> = expected.iter().map(|message| (USER_PHONE_NUMBER.to_string(), message.to_string())).collect();
+        assert_eq!(simulator.sent_messages(), expected);
+    }
+
+    #[tokio::test]
+    async fn init_configures_modem() {
+        let simulator = ModemSimulator::start().unwrap();
+        let _context = start(&simulator, "").await;
+        assert_eq!(simulator.commands(), vec!("AT+CMEE=1", "AT+CLIP=1", "AT+CMGF=0", "AT+CNMI=2,2"));
+    }
+
+    #[tokio::test]
+    async fn request_is_answered() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["close"]).await;
+        assert_responses(&simulator, &["The message you sent is invalid, No open tunnel"]);
+    }
+
+    #[tokio::test]
+    async fn unknown_sender_is_ignored() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        send_requests(&simulator, &mut context, "+33699999999", &["close"]).await;
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["hello"]).await;
+        assert_responses(&simulator, &["The message you sent is invalid, Unknown command: hello"]);
+    }
 
+    #[tokio::test]
+    async fn call_runs_configured_request() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        simulator.inject_call("+33699999999");
+        simulator.inject_call(USER_PHONE_NUMBER);
+        for _ in 0..2 {
+            handle_next_call(&mut context).await;
+        }
+        assert_eq!(simulator.commands().iter().filter(|command| *command == "ATH").count(), 2);
+        assert_responses(&simulator, &["The message you sent is invalid, No open tunnel"]);
+    }
+
+    #[tokio::test]
+    async fn call_is_not_asked_for_confirmation() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        context.configuration.confirmation_config = Some(confirmation::ConfirmationConfig::default());
+        context.configuration.users[0].call = Some("reboot".to_string());
+        simulator.inject_call(USER_PHONE_NUMBER);
+        handle_next_call(&mut context).await;
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["reboot"]).await;
+        let sent_messages = simulator.sent_messages();
+        assert_eq!(sent_messages.len(), 2);
+        assert_eq!(sent_messages[0].1, "Rebooting...");
+        assert!(sent_messages[1].1.starts_with("Reply \"confirm "), "{}", sent_messages[1].1);
+    }
+
+    #[tokio::test]
+    async fn balance_is_queried_by_ussd() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        simulator.set_ussd_response(r#"0,"Votre solde est de 12,50 EUR",15"#);
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["balance"]).await;
+        assert!(simulator.commands().contains(&r#"AT+CUSD=1,"*100#",15"#.to_string()));
+        assert_responses(&simulator, &["Votre solde est de 12,50 EUR"]);
+    }
+
+    #[tokio::test]
+    async fn low_balance_is_alerted() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        //ucs2 response
+        simulator.set_ussd_response(r#"0,"0053006F006C0064006500200033002C0035003000200045005500520020006100750020003100300020006A0075006E",72"#);
+        context.check_balance().await;
+        //check period not elapsed
+        context.check_balance().await;
+        assert_responses(&simulator, &["SIM balance is low: 3.5 (alert threshold: 5)"]);
+    }
+
+    #[tokio::test]
+    async fn stale_sms_is_rejected() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "sms_max_age_sec = 600").await;
+        let date = SystemTime::now() - Duration::from_secs(3600);
+        simulator.inject_sms_received_at(USER_PHONE_NUMBER, "reboot", date);
+        handle_next_sms(&mut context).await;
+        let sent_messages = simulator.sent_messages();
+        assert_eq!(sent_messages.len(), 1);
+        assert!(sent_messages[0].1.ends_with("too long ago to be processed"), "{}", sent_messages[0].1);
+    }
+
+    #[tokio::test]
+    async fn sensitive_command_requires_code() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        context.configuration.users[0].pin = Some("1234".to_string());
+        context.configuration.users[0].code_required_commands = Some(vec!("close".to_string()));
+        context.configuration.rate_limit_config = Some(rate_limit::RateLimitConfig {
+            max_authentication_failures: Some(5),
+            ..Default::default()
+        });
+        //an argument which does not look like a code is not taken for one
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["close", "close 7", "close 9999", "close 1234"]).await;
+        assert_responses(&simulator, &[
+            "Your request cannot be authenticated, a code is required for this command",
+            "Your request cannot be authenticated, a code is required for this command",
+            "Your request cannot be authenticated, the code is invalid",
+            "The message you sent is invalid, No open tunnel",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn command_is_refused_without_permission() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        context.configuration.users[0].role = Some(user::Role::Viewer);
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["shutdown"]).await;
+        context.configuration.users[0].allowed_commands = Some(vec!("close".to_string()));
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["close", "status"]).await;
+        assert_responses(&simulator, &[
+            "Your request is not allowed, you are not allowed to run the shutdown command",
+            "The message you sent is invalid, No open tunnel",
+            "Your request is not allowed, you are not allowed to run the status command",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn application_is_restricted_to_allowed_users() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        context.configuration.users[0].applications = Some(vec!("camera".to_string()));
+        context.status.applications_status.insert("camera".to_string(), ServiceStatus::Reachable);
+        context.status.applications_status.insert("nas".to_string(), ServiceStatus::Reachable);
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["open nas"]).await;
+        assert_responses(&simulator, &["Your request is not allowed, you are not allowed to access nas"]);
+        let status = context.status.to_string_for(&context.configuration.users[0]);
+        assert!(status.ends_with("Apps: camera: OK"), "{}", status);
+    }
+
+    #[tokio::test]
+    async fn tunnel_is_closed_by_its_owner_only() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        context.configuration.users[0].admin = None;
+        context.configuration.users[0].applications = Some(vec!("camera".to_string()));
+        for (reference, user, application) in [(1, "alice", "nas"), (2, "bob", "camera"), (3, "alice", "camera")] {
+            context.tunnels.insert(reference, Tunnel::new(user.to_string(), application.to_string(), sleeping_process(), TIMEOUT));
+        }
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["close 1", "close 2", "close 3"]).await;
+        assert_responses(&simulator, &[
+            "Your request is not allowed, you are not allowed to close tunnel 1",
+            "Your request is not allowed, you are not allowed to close tunnel 2",
+            "Tunnel has been closed",
+        ]);
+        let mut references: Vec<u32> = context.tunnels.keys().copied().collect();
+        references.sort();
+        assert_eq!(references, vec!(1, 2));
+    }
+
+    #[tokio::test]
+    async fn requests_are_rate_limited() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        context.configuration.rate_limit_config = Some(rate_limit::RateLimitConfig {
+            sender_max_requests: Some(2),
+            ..Default::default()
+        });
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["close", "close 1", "close 2", "close 3"]).await;
+        context.report_rate_limiting().await;
+        assert_responses(&simulator, &[
+            "The message you sent is invalid, No open tunnel",
+            "The message you sent is invalid, Unknown tunnel reference: 1",
+            "Your request is rejected, you sent too many requests, retry in 5 minutes",
+            "Rejected requests: alice: 2",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn repeated_authentication_failures_lock_sender_out() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        context.configuration.users[0].pin = Some("1234".to_string());
+        context.configuration.users[0].code_required_commands = Some(vec!("close".to_string()));
+        context.configuration.rate_limit_config = Some(rate_limit::RateLimitConfig {
+            max_authentication_failures: Some(2),
+            ..Default::default()
+        });
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["close 1111", "close 2222", "close 1234"]).await;
+        assert_responses(&simulator, &[
+            "Your request cannot be authenticated, the code is invalid",
+            "User alice is locked out for 15 minutes after repeated authentication failures",
+            "Your request cannot be authenticated, too many authentication failures, your requests are blocked for 15 minutes",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn unknown_senders_count_within_global_window() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        context.configuration.rate_limit_config = Some(rate_limit::RateLimitConfig {
+            global_max_requests: Some(2),
+            ..Default::default()
+        });
+        for sender in ["+33699999999", "+33699999998", USER_PHONE_NUMBER] {
+            send_requests(&simulator, &mut context, sender, &["close"]).await;
+        }
+        assert_responses(&simulator, &["Your request is rejected, too many requests were received, retry in 5 minutes"]);
+    }
+
+    #[tokio::test]
+    async fn call_authentication_failures_do_not_lock_out() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        context.configuration.users[0].pin = Some("1234".to_string());
+        context.configuration.users[0].code_required_commands = Some(vec!("close".to_string()));
+        context.configuration.users[0].call = Some("close".to_string());
+        context.configuration.rate_limit_config = Some(rate_limit::RateLimitConfig {
+            max_authentication_failures: Some(1),
+            ..Default::default()
+        });
+        simulator.inject_call(USER_PHONE_NUMBER);
+        handle_next_call(&mut context).await;
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["close 1234"]).await;
+        assert_responses(&simulator, &[
+            "Your request cannot be authenticated, a code is required for this command",
+            "The message you sent is invalid, No open tunnel",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn requests_are_recorded_in_audit_trail() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        context.configuration.users[0].pin = Some("1234".to_string());
+        context.configuration.users[0].code_required_commands = Some(vec!("close".to_string()));
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["close 3 1234"]).await;
+        send_requests(&simulator, &mut context, "+33699999999", &["status"]).await;
+        let entries = context.audit_log.latest(10);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].user.as_deref(), Some("alice"));
+        assert_eq!(entries[0].command, "close");
+        //the code is not recorded
+        assert_eq!(entries[0].arguments, vec!("3".to_string()));
+        assert_eq!(entries[0].tunnels, vec!(3));
+        assert_eq!(entries[0].outcome, "invalid request: Unknown tunnel reference: 3");
+        assert_eq!(entries[1].user, None);
+        assert_eq!(entries[1].outcome, "sender not allowed: +33699999999");
+
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["audit 1"]).await;
+        let sent_messages = simulator.sent_messages();
+        let response = &sent_messages.last().unwrap().1;
+        assert!(response.ends_with("unknown (+33699999999) status: sender not allowed: +33699999999"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn unknown_senders_are_reported_to_admins() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        context.configuration.unknown_sender_config = Some(unknown_sender::UnknownSenderConfig {
+            digest_channel: Some(unknown_sender::DigestChannel::Sms),
+            digest_period_sec: Some(3600),
+            max_messages_per_sender: Some(2),
+            max_senders: None,
+        });
+        send_requests(&simulator, &mut context, "+33699999999", &["status", "open nas", "reboot"]).await;
+        simulator.inject_call("+33688888888");
+        handle_next_call(&mut context).await;
+        context.report_unknown_senders().await;
+        //digest period not elapsed
+        send_requests(&simulator, &mut context, "+33699999999", &["close"]).await;
+        context.report_unknown_senders().await;
+        assert_responses(&simulator, &["Requests from unknown numbers:\n+33688888888 (1 attempts):\n- (call)\n+33699999999 (3 attempts):\n- status\n- open nas\n- ..."]);
+    }
+
+    #[tokio::test]
+    async fn destructive_command_requires_confirmation() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        context.configuration.confirmation_config = Some(confirmation::ConfirmationConfig {
+            commands: Some(vec!("close".to_string())),
+            window_sec: Some(60),
+        });
+        let confirmation_code = |message: &str| message.split('"').nth(1).unwrap().trim_start_matches("confirm ").to_string();
+
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["close"]).await;
+        let code = confirmation_code(&simulator.sent_messages()[0].1);
+        let invalid_code = format!("{:04}", (code.parse::<u32>().unwrap() + 1) % 10000);
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &[&format!("confirm {}", invalid_code), &format!("confirm {}", code)]).await;
+
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["close 1"]).await;
+        let code = confirmation_code(&simulator.sent_messages()[3].1);
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &[&format!("confirm {}", code)]).await;
+
+        let sent_messages: Vec<String> = simulator.sent_messages().into_iter().map(|(_, message)| message).collect();
+        assert_eq!(sent_messages[1..], vec!(
+            "The message you sent is invalid, Invalid confirmation code, close cancelled".to_string(),
+            "The message you sent is invalid, No request to confirm".to_string(),
+            format!("Reply \"confirm {}\" within 60 seconds to run: close 1", code),
+            "The message you sent is invalid, Unknown tunnel reference: 1".to_string(),
+        ));
+    }
+
+    #[tokio::test]
+    async fn guest_is_invited_and_revoked() {
+        const GUEST_PHONE_NUMBER: &str = "+33611111111";
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        for name in ["nas", "camera"] {
+            context.configuration.applications.push(Application {
+                name: name.to_string(),
+                host_ip: "192.168.1.2".parse().unwrap(),
+                port: 80,
+                end_point: String::new(),
+            });
+        }
+        context.configuration.sms_config.default_country_code = Some("33".to_string());
+        //invitations of the same number in different formats designate the same guest
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["invite 0611111111 bob@example.com camera 1h", &format!("invite {} bob@example.com camera 4h", GUEST_PHONE_NUMBER)]).await;
+        assert_eq!(context.guests.guests.len(), 1);
+        send_requests(&simulator, &mut context, GUEST_PHONE_NUMBER, &["open nas", "reboot", "close"]).await;
+        //guests survive a restart
+        assert_eq!(GuestRegister::load(&context.guests.path).guests.len(), 1);
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["revoke 0611111111"]).await;
+        send_requests(&simulator, &mut context, GUEST_PHONE_NUMBER, &["close 1"]).await;
+        assert!(GuestRegister::load(&context.guests.path).guests.is_empty());
+
+        let sent_messages = simulator.sent_messages();
+        assert_eq!(sent_messages.len(), 8);
+        for invitation in sent_messages[..4].chunks(2) {
+            assert_eq!(invitation[0].0, GUEST_PHONE_NUMBER);
+            assert!(invitation[0].1.starts_with("Hello! alice invited you to access camera until"), "{}", invitation[0].1);
+            assert!(invitation[1].1.starts_with(&format!("{} has been invited to access camera until", GUEST_PHONE_NUMBER)), "{}", invitation[1].1);
+        }
+        assert_eq!(sent_messages[4..], vec!(
+            (GUEST_PHONE_NUMBER.to_string(), "Your request is not allowed, you are not allowed to access nas".to_string()),
+            (GUEST_PHONE_NUMBER.to_string(), "Your request is not allowed, you are not allowed to run the reboot command".to_string()),
+            (GUEST_PHONE_NUMBER.to_string(), "The message you sent is invalid, No open tunnel".to_string()),
+            (USER_PHONE_NUMBER.to_string(), "Access of 0611111111 has been revoked".to_string()),
+        ));
+    }
+
+    #[tokio::test]
+    async fn guest_cannot_close_tunnels_of_others() {
+        const GUEST_PHONE_NUMBER: &str = "+33611111111";
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        context.add_guest(Guest::new(GUEST_PHONE_NUMBER, "bob@example.com", "camera", "alice", Duration::from_secs(3600)));
+        context.tunnels.insert(1, Tunnel::new("alice".to_string(), "camera".to_string(), sleeping_process(), TIMEOUT));
+        send_requests(&simulator, &mut context, GUEST_PHONE_NUMBER, &["close 1", "close"]).await;
+        assert_eq!(simulator.sent_messages(), vec!(
+            (GUEST_PHONE_NUMBER.to_string(), "Your request is not allowed, you are not allowed to close tunnel 1".to_string()),
+            (GUEST_PHONE_NUMBER.to_string(), "The message you sent is invalid, No open tunnel".to_string()),
+        ));
+        assert!(context.tunnels.contains_key(&1));
+    }
+
+    #[tokio::test]
+    async fn expired_guest_is_removed() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        context.add_guest(Guest::new("+33611111111", "bob@example.com", "camera", "alice", Duration::from_secs(0)));
+        assert_eq!(context.configuration.users.len(), 2);
+        context.clean_up_expired_guests().await;
+        assert_eq!(context.configuration.users.len(), 1);
+        assert!(context.guests.guests.is_empty());
+        assert_eq!(simulator.sent_messages(), vec!(
+            ("+33611111111".to_string(), "Your access to camera has expired".to_string()),
+        ));
+    }
+
+    #[tokio::test]
+    async fn duplicate_sms_is_ignored() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        let date = SystemTime::now();
+        simulator.inject_sms_received_at(USER_PHONE_NUMBER, "close", date);
+        simulator.inject_sms_received_at(USER_PHONE_NUMBER, "close", date);
+        for _ in 0..2 {
+            handle_next_sms(&mut context).await;
+        }
+        send_requests(&simulator, &mut context, USER_PHONE_NUMBER, &["hello"]).await;
+        assert_responses(&simulator, &[
+            "The message you sent is invalid, No open tunnel",
+            "The message you sent is invalid, Unknown command: hello",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn stored_sms_are_handled_and_deleted() {
+        let simulator = ModemSimulator::start().unwrap();
+        let _ = simulator.add_stored_sms(USER_PHONE_NUMBER, "close");
+        let mut context = start(&simulator, r#"sms_reception_mode = "store""#).await;
+        let backlog = sms_utils::read_sms_backlog(context.modem.as_ref(), &context.configuration.sms_config, &mut context.concatenated_sms_buffer).await.unwrap();
+        assert_eq!(backlog.len(), 1);
+        for sms in backlog {
+            handle_sms(sms, &mut context).await;
+        }
+        simulator.store_sms(USER_PHONE_NUMBER, "hello");
+        handle_next_sms(&mut context).await;
+        assert_responses(&simulator, &[
+            "The message you sent is invalid, No open tunnel",
+            "The message you sent is invalid, Unknown command: hello",
+        ]);
+        assert!(simulator.stored_indexes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stored_sms_with_lost_notification_is_handled() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, r#"sms_reception_mode = "store""#).await;
+        simulator.store_sms(USER_PHONE_NUMBER, "close");
+        //the +CMTI notification is consumed by a reading interrupted by the periodic routines
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        while context.unsolicited_results.try_recv().is_ok() {}
+        for _ in 0..2 {
+            handle_sms_backlog(&mut context).await;
+        }
+        assert_responses(&simulator, &["The message you sent is invalid, No open tunnel"]);
+        assert!(simulator.stored_indexes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn notification_of_already_handled_sms_is_ignored() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, r#"sms_reception_mode = "store""#).await;
+        //sms read and deleted with the stored sms listing before its +CMTI is handled
+        simulator.notify_stored_sms(5);
+        simulator.store_sms(USER_PHONE_NUMBER, "close");
+        handle_next_sms(&mut context).await;
+        assert_responses(&simulator, &["The message you sent is invalid, No open tunnel"]);
+        assert!(simulator.commands().contains(&"AT+CMGR=5".to_string()));
+    }
+
+    #[tokio::test]
+    async fn lost_modem_configuration_is_recovered() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        assert!(!context.check_modem().await);
+        simulator.restart();
+        assert!(context.check_modem().await);
+        assert!(simulator.commands().ends_with(&["AT+CMEE=1".to_string(), "AT+CLIP=1".to_string(), "AT+CMGF=0".to_string(), "AT+CNMI=2,2".to_string()]));
+        assert!(!context.check_modem().await);
+    }
+
+    #[tokio::test]
+    async fn sms_are_handled_through_uqmi() {
+        let uqmi = UqmiSimulator::start().unwrap();
+        uqmi.store_sms(0, USER_PHONE_NUMBER, "close", None);
+        uqmi.store_sms(1, USER_PHONE_NUMBER, "hel", Some((7, 2, 1)));
+        uqmi.store_sms(2, USER_PHONE_NUMBER, "lo", Some((7, 2, 2)));
+        let mut context = start_qmi(&uqmi).await;
+        let backlog = sms_utils::read_sms_backlog(context.modem.as_ref(), &context.configuration.sms_config, &mut context.concatenated_sms_buffer).await.unwrap();
+        assert_eq!(backlog.len(), 2);
+        for sms in backlog {
+            handle_sms(sms, &mut context).await;
+        }
+        uqmi.store_sms(3, USER_PHONE_NUMBER, "open", None);
+        handle_next_sms(&mut context).await;
+        assert_eq!(uqmi.sent_messages(), vec!(
+            (USER_PHONE_NUMBER.to_string(), "The message you sent is invalid, No open tunnel".to_string()),
+            (USER_PHONE_NUMBER.to_string(), "The message you sent is invalid, Unknown command: hello".to_string()),
+            (USER_PHONE_NUMBER.to_string(), "The message you sent is invalid, No application specified: open".to_string()),
+        ));
+        assert!(uqmi.stored_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn uqmi_timestamp_offset_is_taken_into_account() {
+        let uqmi = UqmiSimulator::start().unwrap();
+        //service centre local time 3 hours behind UTC
+        let local_timestamp = UqmiSimulator::timestamp(SystemTime::now() - Duration::from_secs(3 * 3600));
+        uqmi.store_sms_with_timestamp(0, USER_PHONE_NUMBER, "close", None, &local_timestamp);
+        uqmi.store_sms_with_timestamp(1, USER_PHONE_NUMBER, "hello", None, &format!("{}+00:00", local_timestamp));
+        uqmi.store_sms_with_timestamp(2, USER_PHONE_NUMBER, "open", None, &format!("{}-03:00", local_timestamp));
+        let mut context = start_qmi(&uqmi).await;
+        let backlog = sms_utils::read_sms_backlog(context.modem.as_ref(), &context.configuration.sms_config, &mut context.concatenated_sms_buffer).await.unwrap();
+        for sms in backlog {
+            handle_sms(sms, &mut context).await;
+        }
+        let sent_messages = uqmi.sent_messages();
+        assert_eq!(sent_messages.len(), 3);
+        //without offset, the sms age is counted from its reading
+        assert_eq!(sent_messages[0].1, "The message you sent is invalid, No open tunnel");
+        assert!(sent_messages[1].1.ends_with("too long ago to be processed"), "{}", sent_messages[1].1);
+        assert_eq!(sent_messages[2].1, "The message you sent is invalid, No application specified: open");
+    }
+
+    #[tokio::test]
+    async fn long_response_is_sent_in_parts() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        let message = "Telco-Vecchio ".repeat(20);
+        context.send_sms(OutgoingSms { to: USER_PHONE_NUMBER.to_string(), msg: message.clone() }).await.unwrap();
+        assert_responses(&simulator, &[&message]);
+    }
+
+    #[tokio::test]
+    async fn long_ucs2_response_is_sent_in_parts() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        //70 utf-16 code units fit in a single sms
+        let message = "Привет ".repeat(10);
+        context.send_sms(OutgoingSms { to: USER_PHONE_NUMBER.to_string(), msg: message.clone() }).await.unwrap();
+        //a surrogate pair at a part boundary is sent whole in the next part
+        let long_message = format!("{}😀{}", "ж".repeat(66), "Привет ".repeat(20));
+        context.send_sms(OutgoingSms { to: USER_PHONE_NUMBER.to_string(), msg: long_message.clone() }).await.unwrap();
+        assert_responses(&simulator, &[&message, &long_message]);
+        let parts = simulator.sent_parts();
+        assert_eq!(parts.len(), 5);
+        assert_eq!(parts[1], "ж".repeat(66));
+        assert!(parts[2].starts_with('😀'), "{}", parts[2]);
+        //67 utf-16 code units per concatenated sms part
+        assert_eq!(parts[3].encode_utf16().count(), 67);
+    }
+
+    #[tokio::test]
+    async fn extension_table_characters_are_sent_in_gsm7() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        let message = "Price: 5€ [é] {~|^\\}";
+        context.send_sms(OutgoingSms { to: USER_PHONE_NUMBER.to_string(), msg: message.to_string() }).await.unwrap();
+        //each extension table character taking 2 septets, the message is sent in 2 parts
+        let long_message = "€".repeat(100);
+        context.send_sms(OutgoingSms { to: USER_PHONE_NUMBER.to_string(), msg: long_message.clone() }).await.unwrap();
+        context.send_sms(OutgoingSms { to: USER_PHONE_NUMBER.to_string(), msg: "It’s “fine”…".to_string() }).await.unwrap();
+        assert_responses(&simulator, &[message, &long_message, "It's \"fine\"..."]);
+    }
+
+    #[tokio::test]
+    async fn undelivered_sms_is_sent_again() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "sms_status_report = true").await;
+        assert_eq!(simulator.commands().last().unwrap(), "AT+CNMI=2,2,0,1");
+        context.send_sms(OutgoingSms { to: USER_PHONE_NUMBER.to_string(), msg: "hello".to_string() }).await.unwrap();
+        //message reference 1, permanent failure
+        simulator.inject_status_report("0006010B913316325476F8421010000000004210100000000041");
+        let message = tokio::time::timeout(TIMEOUT, sms_utils::wait_sms(context.modem.as_ref(), &mut context.unsolicited_results, &context.configuration.sms_config, &mut context.concatenated_sms_buffer)).await
+            .unwrap()
+            .unwrap();
+        let IncomingMessage::StatusReport(report) = message else {
+            panic!("status report expected, got {:?}", message);
+        };
+        context.handle_status_report(report).await;
+        assert_eq!(simulator.sent_messages().len(), 2);
+        assert_eq!(context.pending_deliveries.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sms_without_message_reference_is_not_tracked() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "sms_status_report = true").await;
+        simulator.set_message_reference_missing(true);
+        context.send_sms(OutgoingSms { to: USER_PHONE_NUMBER.to_string(), msg: "hello".to_string() }).await.unwrap();
+        assert_eq!(simulator.sent_messages().len(), 1);
+        assert!(context.pending_deliveries.is_empty());
+        assert!(context.outbox.entries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unsent_sms_is_queued_and_sent_again() {
+        let simulator = ModemSimulator::start().unwrap();
+        let mut context = start(&simulator, "").await;
+        simulator.set_sending_failure(true);
+        assert!(context.send_sms(OutgoingSms { to: USER_PHONE_NUMBER.to_string(), msg: "hello".to_string() }).await.is_err());
+        assert_eq!(Outbox::load(&context.outbox.path).entries.len(), 1);
+
+        //retry delay not elapsed
+        simulator.set_sending_failure(false);
+        context.retry_outbox().await;
+        assert!(simulator.sent_messages().is_empty());
+
+        context.outbox.entries[0].next_attempt_date = 0;
+        context.retry_outbox().await;
+        assert_responses(&simulator, &["hello"]);
+        assert!(Outbox::load(&context.outbox.path).entries.is_empty());
+    }
+}
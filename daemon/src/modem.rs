@@ -0,0 +1,438 @@
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+use log::{debug, error, info};
+use serial2_tokio::SerialPort;
+use tokio::sync::{mpsc, oneshot};
+use crate::sms_utils::SmsConfig;
+
+const AT_COMMAND_TIMEOUT_SEC: u64 = 5;
+const READ_BUFFER_SIZE: usize = 128;
+//terminates the data sent after a prompt
+const CTRL_Z: char = '\x1A';
+
+///Results sent by the modem on its own initiative, outside any command response
+#[derive(Debug, PartialEq)]
+pub enum UnsolicitedResult {
+    //+CMT: incoming sms pdu
+    Sms(String),
+    //+CMTI: storage index of an incoming sms
+    StoredSms(u32),
+    //+CDS: status report pdu
+    StatusReport(String),
+    //RING: incoming call
+    Ring,
+    //+CLIP: calling line identification following RING, empty if the number is withheld
+    Call(String),
+}
+
+///Failures of an AT command, CME errors are related to the equipment, CMS errors to the message service,
+/// connection errors are the final result codes of call commands, such as NO CARRIER or BUSY
+#[derive(Debug)]
+pub enum AtError {
+    Io(io::Error),
+    Timeout,
+    ModemUnavailable,
+    Error,
+    CmeError(u16),
+    CmsError(u16),
+    ConnectionError(String),
+}
+
+impl Display for AtError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AtError::Io(e) => write!(f, "serial port error: {}", e),
+            AtError::Timeout => write!(f, "no response"),
+            AtError::ModemUnavailable => write!(f, "modem unavailable"),
+            AtError::Error => write!(f, "ERROR"),
+            AtError::CmeError(code) => write!(f, "+CME ERROR: {}", code),
+            AtError::CmsError(code) => write!(f, "+CMS ERROR: {}", code),
+            AtError::ConnectionError(result_code) => write!(f, "{}", result_code),
+        }
+    }
+}
+
+struct ModemCommand {
+    command: String,
+    //sent once the modem prompts for it
+    data: Option<String>,
+    //prefix of the result line sent after the final result code, such as +CUSD
+    awaited_result: Option<String>,
+    timeout: Duration,
+    response: oneshot::Sender<AtResult>,
+}
+
+pub type AtResult = Result<Vec<String>, AtError>;
+
+///Modem interactions, through AT commands run one after the other
+pub trait Modem: Send + Sync {
+    ///Returns the information lines of the response, once the final result code is received,
+    /// `data` is sent once the modem prompts for it, for commands such as AT+CMGS,
+    /// the line starting with `awaited_result` is awaited even after the final result code, for commands such as AT+CUSD
+    fn execute<'a>(&'a self, command: &'a str, data: Option<&'a str>, awaited_result: Option<&'a str>, timeout: Duration) -> Pin<Box<dyn Future<Output=AtResult> + Send + 'a>>;
+
+    fn at_command<'a>(&'a self, command: &'a str) -> Pin<Box<dyn Future<Output=AtResult> + Send + 'a>> {
+        self.execute(command, None, None, Duration::from_secs(AT_COMMAND_TIMEOUT_SEC))
+    }
+
+    fn at_command_with_timeout<'a>(&'a self, command: &'a str, timeout: Duration) -> Pin<Box<dyn Future<Output=AtResult> + Send + 'a>> {
+        self.execute(command, None, None, timeout)
+    }
+
+    fn at_command_with_data<'a>(&'a self, command: &'a str, data: &'a str, timeout: Duration) -> Pin<Box<dyn Future<Output=AtResult> + Send + 'a>> {
+        self.execute(command, Some(data), None, timeout)
+    }
+
+    fn at_command_awaiting_result<'a>(&'a self, command: &'a str, awaited_result: &'a str, timeout: Duration) -> Pin<Box<dyn Future<Output=AtResult> + Send + 'a>> {
+        self.execute(command, None, Some(awaited_result), timeout)
+    }
+}
+
+///Handle on the modem task, the only one owning the serial port
+#[derive(Clone)]
+pub struct SerialModem {
+    commands: mpsc::UnboundedSender<ModemCommand>,
+}
+
+impl SerialModem {
+    ///Opens the serial port and spawns the task owning it,
+    /// unsolicited results are dispatched on the returned receiver
+    pub fn start(config: &SmsConfig) -> io::Result<(SerialModem, mpsc::UnboundedReceiver<UnsolicitedResult>)> {
+        let serial_port = SerialPort::open(&config.modem_device, serial2::KeepSettings).map_err(|e| {
+            error!("start: cannot open serial port {} - error: {:?}",config.modem_device,e);
+            e
+        })?;
+        info!("start: starting modem task on {}",config.modem_device);
+        let (command_sender, command_receiver) = mpsc::unbounded_channel();
+        let (unsolicited_result_sender, unsolicited_result_receiver) = mpsc::unbounded_channel();
+        let engine = AtEngine {
+            device: config.modem_device.clone(),
+            serial_port,
+            buffer: String::new(),
+            pending_indication: None,
+            unsolicited_results: unsolicited_result_sender,
+        };
+        tokio::spawn(run(engine, command_receiver));
+        Ok((SerialModem { commands: command_sender }, unsolicited_result_receiver))
+    }
+}
+
+impl Modem for SerialModem {
+    fn execute<'a>(&'a self, command: &'a str, data: Option<&'a str>, awaited_result: Option<&'a str>, timeout: Duration) -> Pin<Box<dyn Future<Output=AtResult> + Send + 'a>> {
+        Box::pin(async move {
+            let (response_sender, response_receiver) = oneshot::channel();
+            self.commands.send(ModemCommand {
+                command: command.to_string(),
+                data: data.map(|data| data.to_string()),
+                awaited_result: awaited_result.map(|awaited_result| awaited_result.to_string()),
+                timeout,
+                response: response_sender,
+            }).map_err(|_| {
+                error!("execute: modem task is not running");
+                AtError::ModemUnavailable
+            })?;
+            response_receiver.await.map_err(|_| AtError::ModemUnavailable)?
+        })
+    }
+}
+
+///Stands for the modem when its serial port is left to other programs, AT commands being unavailable
+pub struct UnavailableModem;
+
+impl Modem for UnavailableModem {
+    fn execute<'a>(&'a self, command: &'a str, _data: Option<&'a str>, _awaited_result: Option<&'a str>, _timeout: Duration) -> Pin<Box<dyn Future<Output=AtResult> + Send + 'a>> {
+        Box::pin(async move {
+            error!("execute: modem serial port not used, cannot run command {:?}",command);
+            Err(AtError::ModemUnavailable)
+        })
+    }
+}
+
+async fn run(mut engine: AtEngine, mut commands: mpsc::UnboundedReceiver<ModemCommand>) {
+    loop {
+        tokio::select! {
+            command = commands.recv() => {
+                let Some(command) = command else {
+                    info!("run: all modem handles dropped, stopping modem task");
+                    break;
+                };
+                let result = tokio::time::timeout(command.timeout, engine.execute(&command.command, command.data.as_deref(), command.awaited_result.as_deref())).await
+                    .unwrap_or_else(|_| {
+                        error!("run: timeout while running command {:?}",command.command);
+                        Err(AtError::Timeout)
+                    });
+                let _ = command.response.send(result);
+            }
+            line = engine.read_line() => {
+                match line {
+                    Ok(Line::Text(line)) => {
+                        if !engine.dispatch_unsolicited_result(&line) {
+                            debug!("run: ignoring line {:?}",line);
+                        }
+                    }
+                    Ok(Line::Prompt) => {
+                        debug!("run: ignoring unexpected prompt");
+                    }
+                    Err(e) => {
+                        //the tty disappears while the modem restarts
+                        error!("run: cannot read serial port - error: {:?}",e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        engine.reopen();
+                    }
+                }
+            }
+        }
+    }
+}
+
+enum Line {
+    Text(String),
+    //data requested by the modem
+    Prompt,
+}
+
+///Splits the content received from the modem into lines,
+/// telling command responses from unsolicited results
+struct AtEngine {
+    device: String,
+    serial_port: SerialPort,
+    //content received but not yet split into lines
+    buffer: String,
+    //+CMT or +CDS line, whose pdu is on the next line
+    pending_indication: Option<String>,
+    unsolicited_results: mpsc::UnboundedSender<UnsolicitedResult>,
+}
+
+impl AtEngine {
+    ///Writes the command and returns the response information lines, the command echo being skipped
+    async fn execute(&mut self, command: &str, data: Option<&str>, awaited_result: Option<&str>) -> AtResult {
+        debug!("execute: sending command: {:?}",command);
+        self.serial_port.write_all(format!("{}\r", command).as_bytes()).await.map_err(AtError::Io)?;
+        let mut prompt_expected = data.is_some();
+        let mut information_lines: Vec<String> = vec!();
+        let mut result_awaited = awaited_result.is_some();
+        //final result code received before the awaited result
+        let mut final_result_received = false;
+        loop {
+            let line = match self.read_line().await.map_err(AtError::Io)? {
+                Line::Prompt if prompt_expected => {
+                    prompt_expected = false;
+                    let data = data.unwrap_or_default();
+                    debug!("execute: prompt received, sending data: {:?}",data);
+                    self.serial_port.write_all(format!("{}{}", data, CTRL_Z).as_bytes()).await.map_err(AtError::Io)?;
+                    continue;
+                }
+                Line::Prompt => {
+                    debug!("execute: ignoring unexpected prompt");
+                    continue;
+                }
+                Line::Text(line) => line,
+            };
+            if self.dispatch_unsolicited_result(&line) {
+                continue;
+            }
+            if awaited_result.map(|prefix| line.starts_with(prefix)).unwrap_or(false) {
+                result_awaited = false;
+                if final_result_received {
+                    debug!("execute: awaited result received: {:?}",line);
+                    information_lines.push(line);
+                    break;
+                }
+            }
+            if final_result_received {
+                debug!("execute: ignoring line {:?} while awaiting result",line);
+                continue;
+            }
+            if line == command || Some(line.trim_end_matches(CTRL_Z)) == data {
+                //echo
+                continue;
+            }
+            if let Some(result) = parse_final_result_code(&line) {
+                debug!("execute: final result code received: {:?} - information lines: {:?}",line,information_lines);
+                if result.is_err() || !result_awaited {
+                    return result.map(|_| information_lines);
+                }
+                final_result_received = true;
+                continue;
+            }
+            information_lines.push(line);
+        }
+        Ok(information_lines)
+    }
+
+    ///Opens again the serial port, once the tty is back after a modem restart
+    fn reopen(&mut self) {
+        match SerialPort::open(&self.device, serial2::KeepSettings) {
+            Ok(serial_port) => {
+                info!("reopen: serial port {} reopened",self.device);
+                self.serial_port = serial_port;
+                self.buffer.clear();
+                self.pending_indication = None;
+            }
+            Err(e) => {
+                error!("reopen: cannot open serial port {} - error: {:?}",self.device,e);
+            }
+        }
+    }
+
+    ///Returns the next non empty line, cancelling it does not lose any content
+    async fn read_line(&mut self) -> io::Result<Line> {
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        loop {
+            while let Some(position) = self.buffer.find('\n') {
+                let line = self.buffer[..position].trim().to_string();
+                self.buffer.replace_range(..=position, "");
+                if !line.is_empty() {
+                    return Ok(Line::Text(line));
+                }
+            }
+            //the prompt is not followed by any line ending
+            if self.buffer.trim_start().starts_with('>') {
+                self.buffer.clear();
+                return Ok(Line::Prompt);
+            }
+            let len = self.serial_port.read(&mut buffer).await?;
+            if len == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            self.buffer.push_str(&String::from_utf8_lossy(&buffer[..len]));
+        }
+    }
+
+    ///Returns whether the line is part of an unsolicited result, sending it to the listener once complete
+    fn dispatch_unsolicited_result(&mut self, line: &str) -> bool {
+        let result = if let Some(indication) = self.pending_indication.take() {
+            if indication.starts_with("+CDS:") {
+                UnsolicitedResult::StatusReport(line.to_string())
+            } else {
+                UnsolicitedResult::Sms(line.to_string())
+            }
+        } else if line.starts_with("+CMT:") || line.starts_with("+CDS:") {
+            self.pending_indication = Some(line.to_string());
+            return true;
+        } else if let Some(parameters) = line.strip_prefix("+CMTI:") {
+            //+CMTI: <mem>,<index>
+            match parameters.rsplit(',').next().and_then(|index| index.trim().parse::<u32>().ok()) {
+                Some(index) => UnsolicitedResult::StoredSms(index),
+                None => {
+                    error!("dispatch_unsolicited_result: cannot read storage index from {:?}",line);
+                    return true;
+                }
+            }
+        } else if let Some(parameters) = line.strip_prefix("+CLIP:") {
+            //+CLIP: <number>,<type>[,...]
+            let number = parameters.split(',').next().unwrap_or_default().trim().trim_matches('"');
+            UnsolicitedResult::Call(number.to_string())
+        } else if line == "RING" {
+            UnsolicitedResult::Ring
+        } else {
+            return false;
+        };
+        debug!("dispatch_unsolicited_result: {:?} received",result);
+        if self.unsolicited_results.send(result).is_err() {
+            error!("dispatch_unsolicited_result: no listener, unsolicited result lost");
+        }
+        true
+    }
+}
+
+///Returns none if the line is not a final result code
+fn parse_final_result_code(line: &str) -> Option<Result<(), AtError>> {
+    let parse_code = |code: &str| code.trim().parse::<u16>().ok();
+    if let Some(code) = line.strip_prefix("+CME ERROR:") {
+        return Some(Err(parse_code(code).map(AtError::CmeError).unwrap_or(AtError::Error)));
+    }
+    if let Some(code) = line.strip_prefix("+CMS ERROR:") {
+        return Some(Err(parse_code(code).map(AtError::CmsError).unwrap_or(AtError::Error)));
+    }
+    match line {
+        "OK" => Some(Ok(())),
+        "ERROR" => Some(Err(AtError::Error)),
+        "NO CARRIER" | "BUSY" | "NO ANSWER" | "NO DIALTONE" => Some(Err(AtError::ConnectionError(line.to_string()))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+    use super::*;
+
+    //the pdu of a +CMT unsolicited result
+    const PDU: &str = "07911326040000F0040B911346610089F60000208062917314080CC8F71D14969741F977FD07";
+
+    ///Engine reading a pseudo-terminal, the returned port standing for the modem
+    fn engine() -> (AtEngine, serial2::SerialPort, mpsc::UnboundedReceiver<UnsolicitedResult>) {
+        let (modem_port, device_port) = serial2::SerialPort::pair().unwrap();
+        let device = std::fs::read_link(format!("/proc/self/fd/{}", device_port.as_raw_fd())).unwrap().to_string_lossy().to_string();
+        let (unsolicited_result_sender, unsolicited_result_receiver) = mpsc::unbounded_channel();
+        let engine = AtEngine {
+            serial_port: SerialPort::open(&device, serial2::KeepSettings).unwrap(),
+            device,
+            buffer: String::new(),
+            pending_indication: None,
+            unsolicited_results: unsolicited_result_sender,
+        };
+        (engine, modem_port, unsolicited_result_receiver)
+    }
+
+    ///Writes the parts one after the other, once the modem has received the terminator
+    fn write_after(modem_port: serial2::SerialPort, terminator: char, parts: &'static [&'static str]) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let mut received = String::new();
+            let mut buffer = [0; 64];
+            while !received.contains(terminator) {
+                let len = modem_port.read(&mut buffer).unwrap();
+                received.push_str(&String::from_utf8_lossy(&buffer[..len]));
+            }
+            for part in parts {
+                modem_port.write_all(part.as_bytes()).unwrap();
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn error_result_codes() {
+        let (mut engine, modem_port, _unsolicited_results) = engine();
+        modem_port.write_all(b"\r\n+CME ERROR: 10\r\n").unwrap();
+        assert!(matches!(engine.execute("AT+CPIN?", None, None).await, Err(AtError::CmeError(10))));
+        modem_port.write_all(b"\r\n+CMS ERROR: 321\r\n").unwrap();
+        assert!(matches!(engine.execute("AT+CMGR=1", None, None).await, Err(AtError::CmsError(321))));
+        modem_port.write_all(b"\r\n+CME ERROR: SIM not inserted\r\n").unwrap();
+        assert!(matches!(engine.execute("AT+CPIN?", None, None).await, Err(AtError::Error)));
+        for result_code in ["NO CARRIER", "BUSY", "NO ANSWER", "NO DIALTONE"] {
+            modem_port.write_all(format!("\r\n{}\r\n", result_code).as_bytes()).unwrap();
+            assert!(matches!(engine.execute("ATD+33612345678;", None, None).await, Err(AtError::ConnectionError(code)) if code == result_code));
+        }
+    }
+
+    #[tokio::test]
+    async fn data_is_sent_once_prompted() {
+        let (mut engine, modem_port, _unsolicited_results) = engine();
+        modem_port.write_all(b"\r\n> ").unwrap();
+        let modem = write_after(modem_port.try_clone().unwrap(), CTRL_Z, &["\r\n+CMGS: 5\r\n\r\nOK\r\n"]);
+        assert_eq!(engine.execute("AT+CMGS=23", Some("0011"), None).await.unwrap(), vec!("+CMGS: 5"));
+        modem.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn response_split_across_reads() {
+        let (mut engine, modem_port, _unsolicited_results) = engine();
+        let modem = write_after(modem_port.try_clone().unwrap(), '\r', &["AT+CSQ\r\r\n+CS", "Q: 20,99\r\n\r\nO", "K\r\n"]);
+        assert_eq!(engine.execute("AT+CSQ", None, None).await.unwrap(), vec!("+CSQ: 20,99"));
+        modem.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn unsolicited_result_within_response() {
+        let (mut engine, modem_port, mut unsolicited_results) = engine();
+        modem_port.write_all(format!("\r\n+CSQ: 20,99\r\n\r\n+CMT: ,36\r\n{}\r\n\r\n+CMTI: \"SM\",3\r\n\r\nOK\r\n", PDU).as_bytes()).unwrap();
+        assert_eq!(engine.execute("AT+CSQ", None, None).await.unwrap(), vec!("+CSQ: 20,99"));
+        assert_eq!(unsolicited_results.try_recv().unwrap(), UnsolicitedResult::Sms(PDU.to_string()));
+        assert_eq!(unsolicited_results.try_recv().unwrap(), UnsolicitedResult::StoredSms(3));
+    }
+}
@@ -0,0 +1,178 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use crate::file_utils;
+use crate::file_utils::unix_time;
+use crate::sms_utils::OutgoingSms;
+
+const RETRY_BASE_DELAY_SEC: u64 = 60;
+const RETRY_MAX_DELAY_SEC: u64 = 3600;
+
+///Sms that could not be sent, persisted so that they are sent again later, even after a reboot
+pub struct Outbox {
+    pub path: String,
+    pub entries: Vec<OutboxEntry>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct OutboxEntry {
+    pub to: String,
+    pub msg: String,
+    pub attempts: u32,
+    //seconds since unix epoch
+    pub queuing_date: u64,
+    pub next_attempt_date: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct OutboxContent {
+    #[serde(default)]
+    sms: Vec<OutboxEntry>,
+}
+
+impl Outbox {
+    ///Reads the sms left in the outbox file, the outbox is empty if the file does not exist
+    pub fn load(path: &str) -> Self {
+        let entries = file_utils::load_toml::<OutboxContent>(path).sms;
+        info!("load: {} sms in outbox",entries.len());
+        Outbox { path: path.to_string(), entries }
+    }
+
+    ///Queues sms whose first sending attempt failed
+    pub fn push(&mut self, sms: OutgoingSms) {
+        let now = unix_time(SystemTime::now());
+        self.entries.push(OutboxEntry {
+            to: sms.to,
+            msg: sms.msg,
+            attempts: 1,
+            queuing_date: now,
+            next_attempt_date: now + retry_delay(1),
+        });
+        self.save();
+    }
+
+    ///Removes and returns the entries whose next attempt date is reached
+    pub fn take_due(&mut self, date: SystemTime) -> Vec<OutboxEntry> {
+        let date = unix_time(date);
+        let (due, pending) = std::mem::take(&mut self.entries).into_iter().partition(|entry| entry.next_attempt_date <= date);
+        self.entries = pending;
+        due
+    }
+
+    ///Queues again an entry whose sending attempt failed, delaying its next attempt,
+    /// returns false if the entry is dropped as it has already been attempted `max_attempts` times
+    pub fn reschedule(&mut self, mut entry: OutboxEntry, max_attempts: u32, date: SystemTime) -> bool {
+        if entry.attempts >= max_attempts {
+            return false;
+        }
+        entry.attempts += 1;
+        entry.next_attempt_date = unix_time(date) + retry_delay(entry.attempts);
+        self.entries.push(entry);
+        true
+    }
+
+    pub fn save(&self) {
+        let content = OutboxContent { sms: self.entries.clone() };
+        let result = file_utils::save_toml(&self.path, &content);
+        match result {
+            Ok(()) => debug!("save: {} sms in outbox",self.entries.len()),
+            Err(e) => error!("save: cannot write outbox file {} - error: {:?}",self.path,e),
+        }
+    }
+}
+
+impl OutboxEntry {
+    pub fn sms(&self) -> OutgoingSms {
+        OutgoingSms { to: self.to.clone(), msg: self.msg.clone() }
+    }
+}
+
+impl Display for Outbox {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} SMS in outbox", self.entries.len())?;
+        for entry in &self.entries {
+            writeln!(f, "To: {} - Queued: {} - Attempts: {} - Next attempt: {}",
+                     entry.to,
+                     humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(entry.queuing_date)),
+                     entry.attempts,
+                     humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(entry.next_attempt_date)))?;
+            writeln!(f, "{}", entry.msg)?;
+        }
+        Ok(())
+    }
+}
+
+///Delay doubles with each failed attempt
+fn retry_delay(attempts: u32) -> u64 {
+    RETRY_BASE_DELAY_SEC.saturating_mul(2u64.saturating_pow(attempts.saturating_sub(1))).min(RETRY_MAX_DELAY_SEC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outbox(name: &str) -> Outbox {
+        let path = std::env::temp_dir().join(format!("telco-vecchio-outbox-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Outbox::load(path.to_str().unwrap())
+    }
+
+    fn sms(to: &str) -> OutgoingSms {
+        OutgoingSms { to: to.to_string(), msg: format!("hello {}", to) }
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_max() {
+        let delays: Vec<u64> = (1..=8).map(retry_delay).collect();
+        assert_eq!(delays, vec!(60, 120, 240, 480, 960, 1920, 3600, 3600));
+        assert_eq!(retry_delay(u32::MAX), RETRY_MAX_DELAY_SEC);
+    }
+
+    #[test]
+    fn entries_are_due_after_their_retry_delay() {
+        let mut outbox = outbox("due");
+        outbox.push(sms("+33612345678"));
+        let now = SystemTime::now();
+        assert!(outbox.take_due(now).is_empty());
+        let due = outbox.take_due(now + Duration::from_secs(RETRY_BASE_DELAY_SEC));
+        assert_eq!(due.len(), 1);
+        assert!(outbox.entries.is_empty());
+
+        assert!(outbox.reschedule(due[0].clone(), 10, now));
+        assert_eq!(outbox.entries[0].attempts, 2);
+        assert_eq!(outbox.entries[0].next_attempt_date, unix_time(now) + 120);
+        let _ = std::fs::remove_file(&outbox.path);
+    }
+
+    #[test]
+    fn entry_is_dropped_after_max_attempts() {
+        let mut outbox = outbox("max-attempts");
+        outbox.push(sms("+33612345678"));
+        let mut entry = outbox.take_due(SystemTime::now() + Duration::from_secs(RETRY_BASE_DELAY_SEC)).remove(0);
+        entry.attempts = 2;
+        assert!(outbox.reschedule(entry.clone(), 3, SystemTime::now()));
+        entry.attempts = 3;
+        assert!(!outbox.reschedule(entry, 3, SystemTime::now()));
+        assert_eq!(outbox.entries.len(), 1);
+        let _ = std::fs::remove_file(&outbox.path);
+    }
+
+    #[test]
+    fn outbox_is_saved_and_loaded() {
+        let mut outbox = outbox("persistence");
+        outbox.push(sms("+33612345678"));
+        outbox.push(sms("+33698765432"));
+        assert_eq!(Outbox::load(&outbox.path).entries, outbox.entries);
+
+        //a file whose writing was interrupted in the middle of a value is read as an empty outbox
+        let content = std::fs::read_to_string(&outbox.path).unwrap();
+        std::fs::write(&outbox.path, &content[..content.rfind("hello").unwrap()]).unwrap();
+        assert!(Outbox::load(&outbox.path).entries.is_empty());
+        //the file is replaced once completely written
+        outbox.save();
+        assert_eq!(Outbox::load(&outbox.path).entries.len(), 2);
+        assert!(!std::path::Path::new(&format!("{}.tmp", outbox.path)).exists());
+        let _ = std::fs::remove_file(&outbox.path);
+    }
+}
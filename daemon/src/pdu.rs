@@ -0,0 +1,599 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bitstream_io::{BitReader, LittleEndian};
+use gsm7::{Gsm7Reader, Gsm7Writer};
+use log::debug;
+use crate::common;
+use crate::common::Error::PduParsing;
+
+pub const SMS_MAX_SEPTETS: usize = 160;
+pub const SMS_MAX_OCTETS: usize = 140;
+//a concatenated sms part carries a 6 bytes user data header, padded with 1 fill bit up to 7 septets in gsm 7-bit alphabet
+pub const CONCATENATED_SMS_MAX_SEPTETS: usize = 153;
+pub const CONCATENATED_SMS_MAX_OCTETS: usize = 134;
+pub const CONCATENATED_SMS_HEADER_SEPTETS: usize = 7;
+pub const CONCATENATED_SMS_FILL_BITS: u8 = 1;
+
+//characters of the gsm 7-bit extension table, written as an escape septet followed by their own septet
+const GSM7_EXTENSION_CHARACTERS: &str = "\x0C^{}\\[~]|€";
+
+const USSD_PACKED_GSM7_MIN_OCTETS: usize = 4;
+
+const MESSAGE_TYPE_SMS_DELIVER: u8 = 0x00;
+const MESSAGE_TYPE_SMS_STATUS_REPORT: u8 = 0x02;
+
+///SMS-DELIVER TPDU, as defined in 3GPP TS 23.040, preceded by the service centre address
+#[derive(Debug, PartialEq)]
+pub struct SmsDeliver {
+    pub service_centre_address: Option<Address>,
+    pub more_messages_to_send: bool,
+    pub status_report_indication: bool,
+    pub originating_address: Address,
+    pub protocol_identifier: u8,
+    pub data_coding_scheme: u8,
+    pub service_centre_timestamp: Timestamp,
+    pub user_data_header: Option<UserDataHeader>,
+    pub user_data: String,
+}
+
+///SMS-STATUS-REPORT TPDU, as defined in 3GPP TS 23.040, preceded by the service centre address
+#[derive(Debug, PartialEq)]
+pub struct SmsStatusReport {
+    pub service_centre_address: Option<Address>,
+    pub message_reference: u8,
+    pub recipient_address: Address,
+    pub service_centre_timestamp: Timestamp,
+    pub discharge_time: Timestamp,
+    pub status: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeliveryStatus {
+    Delivered,
+    Pending,
+    Failed,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Address {
+    pub type_of_number: TypeOfNumber,
+    pub numbering_plan: u8,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TypeOfNumber {
+    Unknown,
+    International,
+    National,
+    NetworkSpecific,
+    Subscriber,
+    Alphanumeric,
+    Abbreviated,
+    Reserved,
+}
+
+///Service centre timestamp, `timezone` is expressed in quarters of an hour from GMT
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timestamp {
+    pub year: u8,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub timezone: i8,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UserDataHeader {
+    pub information_elements: Vec<InformationElement>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InformationElement {
+    pub identifier: u8,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConcatenationHeader {
+    pub reference: u16,
+    pub parts_number: u8,
+    pub sequence_number: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alphabet {
+    Gsm7,
+    EightBit,
+    Ucs2,
+}
+
+///Parses a hex encoded SMS-DELIVER pdu as received from the modem in pdu mode
+pub fn parse_sms_deliver(pdu: &str) -> common::Result<SmsDeliver> {
+    debug!("parse_sms_deliver: in: {}",pdu);
+    let bytes = hex::decode(pdu.trim()).map_err(|_| PduParsing("pdu is not hex encoded".to_string()))?;
+    let mut reader = PduReader { bytes: &bytes, position: 0 };
+
+    let service_centre_address = reader.read_service_centre_address()?;
+
+    let first_octet = reader.read_u8("first octet")?;
+    if first_octet & 0x03 != MESSAGE_TYPE_SMS_DELIVER {
+        return Err(PduParsing(format!("unsupported message type: {}", first_octet & 0x03)));
+    }
+    let more_messages_to_send = first_octet & 0x04 == 0;
+    let status_report_indication = first_octet & 0x20 != 0;
+    let user_data_header_indicator = first_octet & 0x40 != 0;
+
+    let originating_address = reader.read_address("originating address")?;
+    let protocol_identifier = reader.read_u8("protocol identifier")?;
+    let data_coding_scheme = reader.read_u8("data coding scheme")?;
+    let service_centre_timestamp = Timestamp::decode(reader.read_bytes(7, "service centre timestamp")?)?;
+
+    let alphabet = Alphabet::from_data_coding_scheme(data_coding_scheme);
+    let user_data_len = reader.read_u8("user data length")? as usize;
+    //user data length is expressed in septets for gsm 7-bit alphabet, in octets otherwise
+    let user_data_octets = match alphabet {
+        Alphabet::Gsm7 => (user_data_len * 7 + 7) / 8,
+        _ => user_data_len,
+    };
+    let user_data_bytes = reader.read_bytes(user_data_octets, "user data")?;
+
+    let (user_data_header, header_octets) = if user_data_header_indicator {
+        let header_len = *user_data_bytes.first().ok_or_else(|| PduParsing("missing user data header length".to_string()))? as usize;
+        let header = user_data_bytes.get(1..1 + header_len).ok_or_else(|| PduParsing("truncated user data header".to_string()))?;
+        (Some(UserDataHeader::decode(header)?), header_len + 1)
+    } else {
+        (None, 0)
+    };
+
+    let user_data = match alphabet {
+        //header is padded with fill bits up to a septet boundary
+        Alphabet::Gsm7 => {
+            let header_septets = (header_octets * 8 + 6) / 7;
+            decode_gsm7(user_data_bytes, header_septets, user_data_len.saturating_sub(header_septets))
+        }
+        Alphabet::EightBit => Ok(decode_8bit(&user_data_bytes[header_octets.min(user_data_bytes.len())..])),
+        Alphabet::Ucs2 => Ok(decode_ucs2(&user_data_bytes[header_octets.min(user_data_bytes.len())..])),
+    }?;
+
+    let sms_deliver = SmsDeliver {
+        service_centre_address,
+        more_messages_to_send,
+        status_report_indication,
+        originating_address,
+        protocol_identifier,
+        data_coding_scheme,
+        service_centre_timestamp,
+        user_data_header,
+        user_data,
+    };
+    debug!("parse_sms_deliver: out: {:?}",sms_deliver);
+    Ok(sms_deliver)
+}
+
+///Parses a hex encoded SMS-STATUS-REPORT pdu as received from the modem in pdu mode
+pub fn parse_sms_status_report(pdu: &str) -> common::Result<SmsStatusReport> {
+    debug!("parse_sms_status_report: in: {}",pdu);
+    let bytes = hex::decode(pdu.trim()).map_err(|_| PduParsing("pdu is not hex encoded".to_string()))?;
+    let mut reader = PduReader { bytes: &bytes, position: 0 };
+
+    let service_centre_address = reader.read_service_centre_address()?;
+    let first_octet = reader.read_u8("first octet")?;
+    if first_octet & 0x03 != MESSAGE_TYPE_SMS_STATUS_REPORT {
+        return Err(PduParsing(format!("unsupported message type: {}", first_octet & 0x03)));
+    }
+    let message_reference = reader.read_u8("message reference")?;
+    let recipient_address = reader.read_address("recipient address")?;
+    let service_centre_timestamp = Timestamp::decode(reader.read_bytes(7, "service centre timestamp")?)?;
+    let discharge_time = Timestamp::decode(reader.read_bytes(7, "discharge time")?)?;
+    let status = reader.read_u8("status")?;
+
+    let sms_status_report = SmsStatusReport {
+        service_centre_address,
+        message_reference,
+        recipient_address,
+        service_centre_timestamp,
+        discharge_time,
+        status,
+    };
+    debug!("parse_sms_status_report: out: {:?}",sms_status_report);
+    Ok(sms_status_report)
+}
+
+impl SmsStatusReport {
+    ///Interprets TP-ST field, the service centre may still be trying to deliver the message on temporary errors
+    pub fn delivery_status(&self) -> DeliveryStatus {
+        match self.status {
+            0x00..=0x1F => DeliveryStatus::Delivered,
+            0x20..=0x3F => DeliveryStatus::Pending,
+            _ => DeliveryStatus::Failed,
+        }
+    }
+}
+
+struct PduReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PduReader<'a> {
+    fn read_u8(&mut self, field: &str) -> common::Result<u8> {
+        Ok(self.read_bytes(1, field)?[0])
+    }
+
+    fn read_bytes(&mut self, len: usize, field: &str) -> common::Result<&'a [u8]> {
+        let bytes = self.bytes.get(self.position..self.position + len).ok_or_else(|| {
+            PduParsing(format!("pdu too short to read {}", field))
+        })?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn read_service_centre_address(&mut self) -> common::Result<Option<Address>> {
+        //service centre address length is expressed in octets, including the type of address
+        let len = self.read_u8("service centre address length")? as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        let type_of_address = self.read_u8("service centre address type")?;
+        let value = self.read_bytes(len - 1, "service centre address")?;
+        Ok(Some(Address::decode(type_of_address, value, (len - 1) * 2)?))
+    }
+
+    fn read_address(&mut self, field: &str) -> common::Result<Address> {
+        //address length is expressed in useful semi-octets
+        let len = self.read_u8(field)? as usize;
+        let type_of_address = self.read_u8(field)?;
+        let value = self.read_bytes((len + 1) / 2, field)?;
+        Address::decode(type_of_address, value, len)
+    }
+}
+
+impl Address {
+    ///`len` is the number of useful semi-octets of the address value
+    fn decode(type_of_address: u8, value: &[u8], len: usize) -> common::Result<Self> {
+        let type_of_number = TypeOfNumber::from((type_of_address >> 4) & 0x07);
+        let numbering_plan = type_of_address & 0x0F;
+        let value = match type_of_number {
+            TypeOfNumber::Alphanumeric => decode_gsm7(value, 0, len * 4 / 7)?,
+            TypeOfNumber::International => format!("+{}", decode_semi_octets(value, len)),
+            _ => decode_semi_octets(value, len),
+        };
+        Ok(Address { type_of_number, numbering_plan, value })
+    }
+}
+
+impl From<u8> for TypeOfNumber {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => TypeOfNumber::Unknown,
+            1 => TypeOfNumber::International,
+            2 => TypeOfNumber::National,
+            3 => TypeOfNumber::NetworkSpecific,
+            4 => TypeOfNumber::Subscriber,
+            5 => TypeOfNumber::Alphanumeric,
+            6 => TypeOfNumber::Abbreviated,
+            _ => TypeOfNumber::Reserved,
+        }
+    }
+}
+
+impl Timestamp {
+    fn decode(bytes: &[u8]) -> common::Result<Self> {
+        let fields: Vec<u8> = bytes.iter().map(|b| (b & 0x0F) * 10 + (b >> 4)).collect();
+        let [year, month, day, hour, minute, second, _] = fields[..] else {
+            return Err(PduParsing("invalid service centre timestamp".to_string()));
+        };
+        //timezone sign is carried by the 4th bit of its tens digit
+        let timezone_byte = bytes[6];
+        let timezone = ((timezone_byte & 0x07) * 10 + (timezone_byte >> 4)) as i8;
+        let timezone = if timezone_byte & 0x08 != 0 { -timezone } else { timezone };
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+            return Err(PduParsing(format!("invalid service centre timestamp: {}", hex::encode(bytes))));
+        }
+        Ok(Timestamp { year, month, day, hour, minute, second, timezone })
+    }
+
+    pub fn to_system_time(self) -> SystemTime {
+        //days since unix epoch of the civil date, march being the first month to ease leap days handling
+        let (month, day) = (self.month as i64, self.day as i64);
+        let year = 2000 + self.year as i64 - if month <= 2 { 1 } else { 0 };
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let days = year * 365 + year / 4 - year / 100 + year / 400 + day_of_year - 719468;
+        let seconds = days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64 - self.timezone as i64 * 15 * 60;
+        UNIX_EPOCH + Duration::from_secs(seconds.max(0) as u64)
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.timezone < 0 { '-' } else { '+' };
+        let timezone_minutes = self.timezone.unsigned_abs() as u32 * 15;
+        write!(f, "20{:02}-{:02}-{:02}T{:02}:{:02}:{:02}{}{:02}:{:02}",
+               self.year, self.month, self.day, self.hour, self.minute, self.second,
+               sign, timezone_minutes / 60, timezone_minutes % 60)
+    }
+}
+
+impl UserDataHeader {
+    fn decode(header: &[u8]) -> common::Result<Self> {
+        let mut information_elements = vec!();
+        let mut i = 0;
+        while i < header.len() {
+            let (identifier, len) = match header.get(i..i + 2) {
+                Some([identifier, len]) => (*identifier, *len as usize),
+                _ => return Err(PduParsing("truncated user data header information element".to_string())),
+            };
+            let data = header.get(i + 2..i + 2 + len).ok_or_else(|| {
+                PduParsing(format!("truncated user data header information element {:02X?}", identifier))
+            })?;
+            information_elements.push(InformationElement { identifier, data: data.to_vec() });
+            i += 2 + len;
+        }
+        Ok(UserDataHeader { information_elements })
+    }
+
+    ///Looks for concatenated sms information element
+    pub fn concatenation(&self) -> Option<ConcatenationHeader> {
+        self.information_elements.iter().find_map(|element| {
+            match (element.identifier, element.data.as_slice()) {
+                //8-bit reference number
+                (0x00, [reference, parts_number, sequence_number]) => {
+                    Some(ConcatenationHeader { reference: *reference as u16, parts_number: *parts_number, sequence_number: *sequence_number })
+                }
+                //16-bit reference number
+                (0x08, [reference_high, reference_low, parts_number, sequence_number]) => {
+                    Some(ConcatenationHeader { reference: u16::from_be_bytes([*reference_high, *reference_low]), parts_number: *parts_number, sequence_number: *sequence_number })
+                }
+                _ => None
+            }
+        })
+    }
+}
+
+impl Alphabet {
+    ///Gsm 7-bit default alphabet is preferred, ucs2 is used as soon as a character is not part of it
+    pub fn select(message: &str) -> Self {
+        let mut writer = Gsm7Writer::new(io::sink());
+        if message.chars().all(|c| writer.write_char(c).is_ok()) {
+            Alphabet::Gsm7
+        } else {
+            Alphabet::Ucs2
+        }
+    }
+
+    ///Reads alphabet from TP-DCS field as defined in 3GPP TS 23.038, reserved values fallback to gsm 7-bit
+    pub fn from_data_coding_scheme(data_coding_scheme: u8) -> Self {
+        let alphabet_bits = match data_coding_scheme >> 4 {
+            //general data coding & automatic deletion groups
+            0x0..=0x7 => (data_coding_scheme >> 2) & 0x03,
+            //message waiting indication group, ucs2
+            0xE => 0x02,
+            //data coding / message class group
+            0xF => (data_coding_scheme >> 2) & 0x01,
+            _ => 0x00,
+        };
+        match alphabet_bits {
+            0x01 => Alphabet::EightBit,
+            0x02 => Alphabet::Ucs2,
+            _ => Alphabet::Gsm7,
+        }
+    }
+
+    pub fn data_coding_scheme(&self) -> u8 {
+        match self {
+            Alphabet::Gsm7 => 0x00,
+            Alphabet::EightBit => 0x04,
+            Alphabet::Ucs2 => 0x08,
+        }
+    }
+
+    ///Length of the character in the alphabet units, septets for gsm 7-bit, octets for 8-bit, 16-bit code units for ucs2
+    pub fn char_len(&self, c: char) -> usize {
+        match self {
+            Alphabet::Gsm7 if GSM7_EXTENSION_CHARACTERS.contains(c) => 2,
+            Alphabet::Ucs2 => c.len_utf16(),
+            _ => 1,
+        }
+    }
+
+    ///Length of the message in the alphabet units, as written in the user data length field when no header is present
+    pub fn message_len(&self, message: &str) -> usize {
+        message.chars().map(|c| self.char_len(c)).sum()
+    }
+
+    pub fn max_len(&self) -> usize {
+        match self {
+            Alphabet::Gsm7 => SMS_MAX_SEPTETS,
+            Alphabet::EightBit => SMS_MAX_OCTETS,
+            Alphabet::Ucs2 => SMS_MAX_OCTETS / 2,
+        }
+    }
+
+    pub fn concatenated_max_len(&self) -> usize {
+        match self {
+            Alphabet::Gsm7 => CONCATENATED_SMS_MAX_SEPTETS,
+            Alphabet::EightBit => CONCATENATED_SMS_MAX_OCTETS,
+            Alphabet::Ucs2 => CONCATENATED_SMS_MAX_OCTETS / 2,
+        }
+    }
+}
+
+///Semi-octets are swapped by pairs, F being the padding digit
+fn decode_semi_octets(bytes: &[u8], len: usize) -> String {
+    bytes.iter()
+        .flat_map(|b| [b & 0x0F, b >> 4])
+        .take(len)
+        .map(|digit| match digit {
+            0..=9 => (b'0' + digit) as char,
+            0x0A => '*',
+            0x0B => '#',
+            0x0C => 'a',
+            0x0D => 'b',
+            0x0E => 'c',
+            _ => 'F',
+        })
+        .filter(|c| *c != 'F')
+        .collect()
+}
+
+///Decodes a USSD string as reported by +CUSD, `data_coding_scheme` being defined in 3GPP TS 23.038,
+/// gsm 7-bit strings are converted to text by the modem, other ones are hex encoded
+pub fn decode_ussd(text: &str, data_coding_scheme: u8) -> String {
+    let bytes = hex::decode(text);
+    match (Alphabet::from_data_coding_scheme(data_coding_scheme), bytes) {
+        (Alphabet::Ucs2, Ok(bytes)) => decode_ucs2(&bytes),
+        (Alphabet::EightBit, Ok(bytes)) => decode_8bit(&bytes),
+        //some modems report gsm 7-bit strings packed and hex encoded instead of converting them,
+        // readable responses being unlikely made of hex digits only, and numeric ones being kept as is
+        (Alphabet::Gsm7, Ok(bytes)) if bytes.len() >= USSD_PACKED_GSM7_MIN_OCTETS && text.chars().any(|c| c.is_ascii_alphabetic()) => {
+            //7 spare bits at the end of the string are filled with a carriage return
+            decode_gsm7(&bytes, 0, bytes.len() * 8 / 7)
+                .map(|decoded| decoded.trim_end_matches('\r').to_string())
+                //a decoded string with control characters was not packed
+                .ok().filter(|decoded| !decoded.chars().any(|c| c.is_control() && c != '\r' && c != '\n'))
+                .unwrap_or_else(|| text.to_string())
+        }
+        _ => text.to_string(),
+    }
+}
+
+///`header_septets` are the septets occupied by the user data header, if any, and skipped
+fn decode_gsm7(bytes: &[u8], header_septets: usize, size_septets: usize) -> common::Result<String> {
+    let mut bit_reader = BitReader::endian(io::Cursor::new(bytes), LittleEndian);
+    bit_reader.skip(header_septets as u32 * 7).map_err(|_| PduParsing("user data shorter than its header".to_string()))?;
+    let mut out = Gsm7Reader::from(bit_reader).collect::<io::Result<String>>().map_err(|_| {
+        PduParsing("invalid gsm 7-bit user data".to_string())
+    })?;
+    //7 spare bits at the end of the user data would be read as an extra character
+    if bytes.len() * 8 >= (header_septets + size_septets) * 7 + 7 {
+        let _ = out.pop();
+    }
+    Ok(out)
+}
+
+fn decode_ucs2(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes.chunks_exact(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+///8-bit data has no defined character set, octets are read as latin-1 characters
+fn decode_8bit(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| *b as char).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_gsm7_sms_deliver() {
+        let sms = parse_sms_deliver("07911326040000F0040B911346610089F60000208062917314080CC8F71D14969741F977FD07").unwrap();
+        assert_eq!(sms.service_centre_address, Some(Address { type_of_number: TypeOfNumber::International, numbering_plan: 1, value: "+31624000000".to_string() }));
+        assert_eq!(sms.originating_address, Address { type_of_number: TypeOfNumber::International, numbering_plan: 1, value: "+31641600986".to_string() });
+        assert_eq!(sms.protocol_identifier, 0);
+        assert_eq!(sms.data_coding_scheme, 0);
+        assert_eq!(sms.service_centre_timestamp, Timestamp { year: 2, month: 8, day: 26, hour: 19, minute: 37, second: 41, timezone: 0 });
+        assert_eq!(sms.user_data_header, None);
+        assert_eq!(sms.user_data, "How are you?");
+    }
+
+    #[test]
+    fn parse_alphanumeric_sender() {
+        let sms = parse_sms_deliver("0791448720003023240DD0E474D81C0EBB010000111011315214000BE474D81C0EBB5DE3771B").unwrap();
+        assert_eq!(sms.originating_address.type_of_number, TypeOfNumber::Alphanumeric);
+        assert_eq!(sms.originating_address.value, "diafaan");
+        assert!(sms.status_report_indication);
+        assert_eq!(sms.user_data, "diafaan.com");
+    }
+
+    #[test]
+    fn parse_national_sender_without_service_centre_address() {
+        let sms = parse_sms_deliver("00040AA1602143658700004210707135728002E834").unwrap();
+        assert_eq!(sms.service_centre_address, None);
+        assert_eq!(sms.originating_address, Address { type_of_number: TypeOfNumber::National, numbering_plan: 1, value: "0612345678".to_string() });
+        assert_eq!(sms.user_data, "hi");
+    }
+
+    #[test]
+    fn parse_concatenated_ucs2_sms_deliver() {
+        let sms = parse_sms_deliver("07913396050066F0440B913306123456F8000842107071357280120500030A0201007300740061007400750073").unwrap();
+        assert_eq!(sms.user_data_header.as_ref().and_then(|header| header.concatenation()),
+                   Some(ConcatenationHeader { reference: 10, parts_number: 2, sequence_number: 1 }));
+        assert_eq!(sms.user_data, "status");
+        assert_eq!(sms.service_centre_timestamp.to_string(), "2024-01-07T17:53:27+02:00");
+    }
+
+    #[test]
+    fn parse_concatenated_gsm7_sms_deliver() {
+        let sms = parse_sms_deliver("07913396050066F0440B913306123456F80000421070713572800B0500030A0202E0E1F91C").unwrap();
+        assert_eq!(sms.user_data_header.as_ref().and_then(|header| header.concatenation()),
+                   Some(ConcatenationHeader { reference: 10, parts_number: 2, sequence_number: 2 }));
+        assert_eq!(sms.user_data, "pass");
+    }
+
+    #[test]
+    fn convert_timestamp_to_system_time() {
+        let timestamp = Timestamp { year: 24, month: 1, day: 7, hour: 17, minute: 53, second: 28, timezone: 8 };
+        assert_eq!(timestamp.to_system_time(), UNIX_EPOCH + Duration::from_secs(1704642808));
+        let timestamp = Timestamp { year: 24, month: 2, day: 29, hour: 0, minute: 15, second: 0, timezone: -4 };
+        assert_eq!(timestamp.to_system_time(), UNIX_EPOCH + Duration::from_secs(1709169300));
+    }
+
+    #[test]
+    fn parse_status_report() {
+        let report = parse_sms_status_report("00062A0B913316325476F8421070713572804210707135828000").unwrap();
+        assert_eq!(report.message_reference, 0x2A);
+        assert_eq!(report.recipient_address.value, "+33612345678");
+        assert_eq!(report.discharge_time, Timestamp { year: 24, month: 1, day: 7, hour: 17, minute: 53, second: 28, timezone: 8 });
+        assert_eq!(report.delivery_status(), DeliveryStatus::Delivered);
+        let report = parse_sms_status_report("00062B0B913316325476F8421070713572804210707135828046").unwrap();
+        assert_eq!(report.delivery_status(), DeliveryStatus::Failed);
+        assert!(matches!(parse_sms_status_report("07911326040000F0040B911346610089F60000208062917314080CC8F71D14969741F977FD07"), Err(PduParsing(_))));
+    }
+
+    #[test]
+    fn gsm7_extension_characters_take_two_septets() {
+        assert_eq!(Alphabet::select("5€ [é]"), Alphabet::Gsm7);
+        assert_eq!(Alphabet::Gsm7.message_len("5€ [é]"), 9);
+        assert_eq!(Alphabet::Gsm7.message_len("{}~|^\\"), 12);
+        assert_eq!(Alphabet::select("ça"), Alphabet::Ucs2);
+    }
+
+    #[test]
+    fn decode_ussd_strings() {
+        assert_eq!(decode_ussd("Solde: 3,50 EUR", 15), "Solde: 3,50 EUR");
+        assert_eq!(decode_ussd("C8329BFD06", 15), "Hello");
+        assert_eq!(decode_ussd("0053006F006C0064006500200033002C00350030002020AC", 72), "Solde 3,50 €");
+    }
+
+    #[test]
+    fn decode_ussd_keeps_plain_text_made_of_hex_digits() {
+        //numeric replies
+        assert_eq!(decode_ussd("0612345678", 15), "0612345678");
+        assert_eq!(decode_ussd("20241231", 15), "20241231");
+        //too short to be packed
+        assert_eq!(decode_ussd("CAFE", 15), "CAFE");
+        //odd number of hex digits
+        assert_eq!(decode_ussd("ABCDE", 15), "ABCDE");
+        //hex strings are decoded as ucs-2 and 8-bit data whatever their content
+        assert_eq!(decode_ussd("00410042", 72), "AB");
+        assert_eq!(decode_ussd("4142", 4), "AB");
+        //malformed ucs-2 is kept as is
+        assert_eq!(decode_ussd("Solde", 72), "Solde");
+    }
+
+    #[test]
+    fn reject_malformed_pdus() {
+        assert!(matches!(parse_sms_deliver("not hex"), Err(PduParsing(_))));
+        assert!(matches!(parse_sms_deliver("07911326040000F0040B9113466100"), Err(PduParsing(_))));
+        assert!(matches!(parse_sms_deliver("07911326040000F0040B911346610089F60000208062917314080CC8F71D"), Err(PduParsing(_))));
+        //sms-status-report
+        assert!(matches!(parse_sms_deliver("07911326040000F0060B911346610089F60000208062917314080CC8F71D14969741F977FD07"), Err(PduParsing(_))));
+    }
+}
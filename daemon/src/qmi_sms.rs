@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+use log::debug;
+use tinyjson::JsonValue;
+use crate::common;
+use crate::common::Error::QmiResponseParsing;
+use crate::pdu::{ConcatenationHeader, Timestamp};
+use crate::status::QmiProvider;
+
+const MAX_TIMEZONE_OFFSET_HOURS: u32 = 14;
+
+///Sms read through uqmi, already decoded, `sending_date` being known only when uqmi reports the timestamp offset
+#[derive(Debug, PartialEq)]
+pub struct QmiMessage {
+    pub sender: String,
+    pub timestamp: Timestamp,
+    pub sending_date: Option<SystemTime>,
+    pub text: String,
+    pub concatenation: Option<ConcatenationHeader>,
+}
+
+///Sms interactions through uqmi, as an alternative to AT commands on the modem serial port
+impl QmiProvider {
+    ///Returns the identifiers of the sms stored on the modem
+    pub async fn list_messages(&self) -> common::Result<Vec<u32>> {
+        let output = self.qmi_command("--list-messages", vec!()).await?;
+        let ids = parse_message_list(&output)?;
+        debug!("list_messages: {:?}",ids);
+        Ok(ids)
+    }
+
+    pub async fn get_message(&self, id: u32) -> common::Result<QmiMessage> {
+        let output = self.qmi_command("--get-message", vec!(id.to_string().as_str())).await?;
+        let message = parse_message(&output)?;
+        debug!("get_message: message {}: {:?}",id,message);
+        Ok(message)
+    }
+
+    pub async fn delete_message(&self, id: u32) -> common::Result<()> {
+        let _ = self.qmi_command("--delete-message", vec!(id.to_string().as_str())).await?;
+        Ok(())
+    }
+
+    ///Message encoding is left to uqmi
+    pub async fn send_message(&self, to: &str, text: &str) -> common::Result<()> {
+        let _ = self.qmi_command("--send-message", vec!(text, "--send-message-target", to)).await?;
+        Ok(())
+    }
+}
+
+///Reads the identifiers of a --list-messages output, a json array, uqmi printing nothing when there is no message
+fn parse_message_list(output: &str) -> common::Result<Vec<u32>> {
+    if output.trim().is_empty() {
+        return Ok(vec!());
+    }
+    let json: JsonValue = output.parse().map_err(|_| QmiResponseParsing("cannot parse --list-messages response into json".to_string()))?;
+    let ids: &Vec<JsonValue> = json.get().ok_or(QmiResponseParsing("cannot read message list".to_string()))?;
+    Ok(ids.iter().filter_map(|id| id.get::<f64>().map(|id| *id as u32)).collect())
+}
+
+///Reads a --get-message output, a json object whose concatenation fields are set for the parts of concatenated sms only
+fn parse_message(output: &str) -> common::Result<QmiMessage> {
+    let json: JsonValue = output.parse().map_err(|_| QmiResponseParsing("cannot parse --get-message response into json".to_string()))?;
+    let fields: &HashMap<String, JsonValue> = json.get().ok_or(QmiResponseParsing("cannot read message".to_string()))?;
+    let string_field = |name: &str| -> common::Result<String> {
+        fields.get(name).and_then(|value| value.get::<String>()).cloned()
+            .ok_or(QmiResponseParsing(format!("cannot read message {}", name)))
+    };
+    let number_field = |name: &str| fields.get(name).and_then(|value| value.get::<f64>()).copied();
+    let concatenation = match (number_field("concat_ref"), number_field("concat_parts"), number_field("concat_part")) {
+        (Some(reference), Some(parts_number), Some(sequence_number)) => Some(ConcatenationHeader {
+            reference: reference as u16,
+            parts_number: parts_number as u8,
+            sequence_number: sequence_number as u8,
+        }),
+        _ => None,
+    };
+    let (timestamp, timezone_known) = parse_timestamp(&string_field("timestamp")?)?;
+    Ok(QmiMessage {
+        sender: string_field("sender")?,
+        timestamp,
+        sending_date: timezone_known.then(|| timestamp.to_system_time()),
+        text: string_field("text")?,
+        concatenation,
+    })
+}
+
+///Reads `YYYY-MM-DD HH:MM:SS` timestamps, optionally followed by a `+HH:MM` or `-HH:MM` offset,
+/// returns whether the offset is given, uqmi printing the service centre local time without it
+fn parse_timestamp(timestamp: &str) -> common::Result<(Timestamp, bool)> {
+    let invalid = || QmiResponseParsing(format!("invalid message timestamp: {}", timestamp));
+    //the offset sign follows the time, date fields being separated by '-' as well
+    let (date_time, offset) = match timestamp.char_indices().skip(10).find(|(_, c)| *c == '+' || *c == '-') {
+        Some((index, _)) => (&timestamp[..index], Some(&timestamp[index..])),
+        None => (timestamp, None),
+    };
+    let fields: Vec<u32> = date_time.split(|c: char| !c.is_ascii_digit())
+        .filter(|field| !field.is_empty())
+        .filter_map(|field| field.parse::<u32>().ok())
+        .collect();
+    let [year, month, day, hour, minute, second] = fields[..] else {
+        return Err(invalid());
+    };
+    let timezone = match offset {
+        None => 0,
+        Some(offset) => {
+            let digits: Vec<u32> = offset.chars().filter_map(|c| c.to_digit(10)).collect();
+            let (hours, minutes) = match digits[..] {
+                [hours_tens, hours] => (hours_tens * 10 + hours, 0),
+                [hours_tens, hours, minutes_tens, minutes] => (hours_tens * 10 + hours, minutes_tens * 10 + minutes),
+                _ => return Err(invalid()),
+            };
+            if hours > MAX_TIMEZONE_OFFSET_HOURS || minutes >= 60 {
+                return Err(invalid());
+            }
+            let quarters = (hours * 4 + minutes / 15) as i8;
+            if offset.starts_with('-') { -quarters } else { quarters }
+        }
+    };
+    Ok((Timestamp {
+        year: (year % 100) as u8,
+        month: month as u8,
+        day: day as u8,
+        hour: hour as u8,
+        minute: minute as u8,
+        second: second as u8,
+        timezone,
+    }, offset.is_some()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_list_is_parsed() {
+        assert_eq!(parse_message_list("[0,3,12]\n").unwrap(), vec!(0, 3, 12));
+        assert!(parse_message_list("").unwrap().is_empty());
+        assert!(parse_message_list("[]").unwrap().is_empty());
+        assert!(parse_message_list("error").is_err());
+    }
+
+    #[test]
+    fn message_is_parsed() {
+        let message = parse_message(r#"{"smsc":"+33609001390","sender":"+33612345678","timestamp":"2024-03-05 14:07:09","text":"status"}"#).unwrap();
+        assert_eq!(message, QmiMessage {
+            sender: "+33612345678".to_string(),
+            timestamp: Timestamp { year: 24, month: 3, day: 5, hour: 14, minute: 7, second: 9, timezone: 0 },
+            sending_date: None,
+            text: "status".to_string(),
+            concatenation: None,
+        });
+        let part = parse_message(r#"{"sender":"+33612345678","timestamp":"2024-03-05 14:07:09+01:00","text":"hel","concat_ref":7,"concat_parts":2,"concat_part":1}"#).unwrap();
+        assert_eq!(part.concatenation, Some(ConcatenationHeader { reference: 7, parts_number: 2, sequence_number: 1 }));
+        assert_eq!(part.sending_date, Some(part.timestamp.to_system_time()));
+        assert!(parse_message(r#"{"sender":"+33612345678","text":"status"}"#).is_err());
+    }
+
+    #[test]
+    fn timestamp_offsets_are_parsed() {
+        let (timestamp, timezone_known) = parse_timestamp("2024-03-05 14:07:09").unwrap();
+        assert_eq!((timestamp.timezone, timezone_known), (0, false));
+        assert_eq!(parse_timestamp("2024-03-05 14:07:09+02:00").unwrap().0.timezone, 8);
+        assert_eq!(parse_timestamp("2024-03-05 14:07:09-05:30").unwrap().0.timezone, -22);
+        assert_eq!(parse_timestamp("2024-03-05 14:07:09+01").unwrap().0.timezone, 4);
+        assert!(parse_timestamp("2024-03-05 14:07:09+15:00").is_err());
+        assert!(parse_timestamp("2024-03-05").is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use crate::common;
+use crate::common::Error::RateLimitExceeded;
+
+pub const DEFAULT_SENDER_MAX_REQUESTS: usize = 5;
+pub const DEFAULT_SENDER_WINDOW_SEC: u64 = 300;
+pub const DEFAULT_GLOBAL_MAX_REQUESTS: usize = 20;
+pub const DEFAULT_GLOBAL_WINDOW_SEC: u64 = 300;
+pub const DEFAULT_MAX_AUTHENTICATION_FAILURES: usize = 3;
+pub const DEFAULT_LOCKOUT_DURATION_SEC: u64 = 900;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct RateLimitConfig {
+    pub sender_max_requests: Option<usize>,
+    pub sender_window_sec: Option<u64>,
+    pub global_max_requests: Option<usize>,
+    pub global_window_sec: Option<u64>,
+    pub max_authentication_failures: Option<usize>,
+    pub lockout_duration_sec: Option<u64>,
+}
+
+impl RateLimitConfig {
+    pub fn lockout_duration(&self) -> Duration {
+        Duration::from_secs(self.lockout_duration_sec.unwrap_or(DEFAULT_LOCKOUT_DURATION_SEC))
+    }
+
+    fn sender_window(&self) -> Duration {
+        Duration::from_secs(self.sender_window_sec.unwrap_or(DEFAULT_SENDER_WINDOW_SEC))
+    }
+
+    fn global_window(&self) -> Duration {
+        Duration::from_secs(self.global_window_sec.unwrap_or(DEFAULT_GLOBAL_WINDOW_SEC))
+    }
+}
+
+///Requests accepted within the sliding windows, authentication failures and lockouts, per user name or per number for unknown senders,
+/// senders being forgotten once they have nothing left within the windows, so that spoofed numbers do not pile up
+#[derive(Default)]
+pub struct RateLimiter {
+    sender_requests: HashMap<String, VecDeque<SystemTime>>,
+    global_requests: VecDeque<SystemTime>,
+    //users already told their requests are limited with the notification date,
+    // to be told again once a request is accepted or the longest window has elapsed
+    notified_senders: HashMap<String, SystemTime>,
+    authentication_failures: HashMap<String, VecDeque<SystemTime>>,
+    lockouts: HashMap<String, SystemTime>,
+    //rejected requests since the last summary
+    rejected_requests: BTreeMap<String, u32>,
+}
+
+impl RateLimiter {
+    ///Accepts the request if the user is not locked out and the limits are not reached,
+    /// only the first rejected request being answered
+    pub fn check_request(&mut self, config: &RateLimitConfig, user: &str, now: SystemTime) -> common::Result<()> {
+        self.prune(config, now);
+        if self.lockouts.contains_key(user) {
+            error!("check_request - {} is locked out",user);
+            *self.rejected_requests.entry(user.to_string()).or_default() += 1;
+            return Err(RateLimitExceeded(None));
+        }
+
+        let sender_window = config.sender_window();
+        let global_window = config.global_window();
+        let sender_requests_count = self.sender_requests.get(user).map(|requests| requests.len()).unwrap_or(0);
+        let reason = if sender_requests_count >= config.sender_max_requests.unwrap_or(DEFAULT_SENDER_MAX_REQUESTS) {
+            Some(format!("you sent too many requests, retry in {} minutes", minutes(sender_window)))
+        } else if self.global_requests.len() >= config.global_max_requests.unwrap_or(DEFAULT_GLOBAL_MAX_REQUESTS) {
+            Some(format!("too many requests were received, retry in {} minutes", minutes(global_window)))
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            error!("check_request - request from {} rejected: {}",user,reason);
+            *self.rejected_requests.entry(user.to_string()).or_default() += 1;
+            let first_rejection = self.notified_senders.insert(user.to_string(), now).is_none();
+            return Err(RateLimitExceeded(first_rejection.then_some(reason)));
+        }
+
+        self.sender_requests.entry(user.to_string()).or_default().push_back(now);
+        self.global_requests.push_back(now);
+        self.notified_senders.remove(user);
+        Ok(())
+    }
+
+    ///Forgets the requests and failures older than their window, the expired lockouts
+    /// and the senders with nothing left to be remembered
+    fn prune(&mut self, config: &RateLimitConfig, now: SystemTime) {
+        let sender_window = config.sender_window();
+        let global_window = config.global_window();
+        let lockout_duration = config.lockout_duration();
+        remove_older(&mut self.global_requests, now, global_window);
+        self.sender_requests.retain(|_, requests| {
+            remove_older(requests, now, sender_window);
+            !requests.is_empty()
+        });
+        self.authentication_failures.retain(|_, failures| {
+            remove_older(failures, now, lockout_duration);
+            !failures.is_empty()
+        });
+        self.lockouts.retain(|_, end| *end > now);
+        let notification_window = sender_window.max(global_window);
+        self.notified_senders.retain(|_, date| now.duration_since(*date).map(|age| age < notification_window).unwrap_or(true));
+    }
+
+    ///Records an authentication failure, returns true if the user has just been locked out
+    pub fn record_authentication_failure(&mut self, config: &RateLimitConfig, user: &str, now: SystemTime) -> bool {
+        let lockout_duration = config.lockout_duration();
+        let failures = self.authentication_failures.entry(user.to_string()).or_default();
+        remove_older(failures, now, lockout_duration);
+        failures.push_back(now);
+        if failures.len() < config.max_authentication_failures.unwrap_or(DEFAULT_MAX_AUTHENTICATION_FAILURES) {
+            return false;
+        }
+        info!("record_authentication_failure - {} locked out after {} failures",user,failures.len());
+        failures.clear();
+        self.lockouts.insert(user.to_string(), now + lockout_duration);
+        true
+    }
+
+    ///Describes the requests rejected since the previous summary, if any
+    pub fn take_summary(&mut self) -> Option<String> {
+        if self.rejected_requests.is_empty() {
+            return None;
+        }
+        let counts: Vec<String> = self.rejected_requests.iter().map(|(user, count)| format!("{}: {}", user, count)).collect();
+        self.rejected_requests.clear();
+        Some(format!("Rejected requests: {}", counts.join(" - ")))
+    }
+}
+
+fn remove_older(dates: &mut VecDeque<SystemTime>, now: SystemTime, window: Duration) {
+    while dates.front().map(|date| now.duration_since(*date).map(|age| age >= window).unwrap_or(false)).unwrap_or(false) {
+        dates.pop_front();
+    }
+}
+
+///Whole minutes, rounded up so that a sender is never told to retry too early
+pub fn minutes(duration: Duration) -> u64 {
+    (duration.as_secs() + 59) / 60
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            sender_max_requests: Some(2),
+            sender_window_sec: Some(60),
+            global_max_requests: Some(3),
+            global_window_sec: Some(120),
+            max_authentication_failures: Some(2),
+            lockout_duration_sec: Some(90),
+        }
+    }
+
+    fn message(result: common::Result<()>) -> Option<String> {
+        match result {
+            Err(RateLimitExceeded(message)) => message,
+            _ => panic!("rejection expected, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn sender_requests_are_limited_within_window() {
+        let (config, now) = (config(), SystemTime::now());
+        let mut rate_limiter = RateLimiter::default();
+        assert!(rate_limiter.check_request(&config, "alice", now).is_ok());
+        assert!(rate_limiter.check_request(&config, "alice", now).is_ok());
+        //only the first rejected request is answered
+        assert_eq!(message(rate_limiter.check_request(&config, "alice", now)), Some("you sent too many requests, retry in 1 minutes".to_string()));
+        assert_eq!(message(rate_limiter.check_request(&config, "alice", now)), None);
+        assert!(rate_limiter.check_request(&config, "alice", now + Duration::from_secs(60)).is_ok());
+        assert_eq!(rate_limiter.take_summary(), Some("Rejected requests: alice: 2".to_string()));
+        assert_eq!(rate_limiter.take_summary(), None);
+    }
+
+    #[test]
+    fn global_requests_are_limited_within_window() {
+        let (config, now) = (config(), SystemTime::now());
+        let mut rate_limiter = RateLimiter::default();
+        for sender in ["+33600000001", "+33600000002", "+33600000003"] {
+            assert!(rate_limiter.check_request(&config, sender, now).is_ok());
+        }
+        assert_eq!(message(rate_limiter.check_request(&config, "alice", now)), Some("too many requests were received, retry in 2 minutes".to_string()));
+        assert!(rate_limiter.check_request(&config, "alice", now + Duration::from_secs(120)).is_ok());
+    }
+
+    #[test]
+    fn repeated_authentication_failures_lock_out_until_expiry() {
+        let (config, now) = (config(), SystemTime::now());
+        let mut rate_limiter = RateLimiter::default();
+        assert!(!rate_limiter.record_authentication_failure(&config, "alice", now));
+        assert!(rate_limiter.record_authentication_failure(&config, "alice", now));
+        assert_eq!(message(rate_limiter.check_request(&config, "alice", now + Duration::from_secs(89))), None);
+        assert!(rate_limiter.check_request(&config, "bob", now).is_ok());
+        assert!(rate_limiter.check_request(&config, "alice", now + Duration::from_secs(90)).is_ok());
+        //failures older than the lockout duration are forgotten
+        assert!(!rate_limiter.record_authentication_failure(&config, "alice", now + Duration::from_secs(100)));
+        assert!(!rate_limiter.record_authentication_failure(&config, "alice", now + Duration::from_secs(200)));
+    }
+
+    #[test]
+    fn senders_are_forgotten_once_windows_have_elapsed() {
+        let (config, now) = (config(), SystemTime::now());
+        let mut rate_limiter = RateLimiter::default();
+        for i in 0..50 {
+            let _ = rate_limiter.check_request(&config, &format!("+336000000{:02}", i), now);
+        }
+        rate_limiter.record_authentication_failure(&config, "alice", now);
+        rate_limiter.record_authentication_failure(&config, "alice", now);
+        assert_eq!(rate_limiter.sender_requests.len(), 3);
+        assert_eq!(rate_limiter.notified_senders.len(), 47);
+        assert_eq!(rate_limiter.lockouts.len(), 1);
+
+        let _ = rate_limiter.check_request(&config, "bob", now + Duration::from_secs(120));
+        assert_eq!(rate_limiter.sender_requests.keys().collect::<Vec<&String>>(), vec!("bob"));
+        assert!(rate_limiter.notified_senders.is_empty());
+        assert!(rate_limiter.authentication_failures.is_empty());
+        assert!(rate_limiter.lockouts.is_empty());
+    }
+
+    #[test]
+    fn minutes_are_rounded_up() {
+        assert_eq!(minutes(Duration::from_secs(60)), 1);
+        assert_eq!(minutes(Duration::from_secs(90)), 2);
+        assert_eq!(minutes(Duration::from_secs(900)), 15);
+    }
+}
@@ -1,31 +1,134 @@
 use std::process::Command;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use log::{debug, error, info};
-use crate::{common, Context, email_utils, init, ssh_utils};
-use crate::common::{Error, Tunnel};
+use crate::{auth, common, Context, rate_limit, email_utils, init, sms_utils, ssh_utils, ussd_utils};
+use crate::audit::AuditEntry;
+use crate::common::{Configuration, Error, Tunnel};
 use crate::email_utils::OutgoingEmail;
+use crate::guest::Guest;
+use crate::sms_utils::OutgoingSms;
 use crate::status::{DeviceStatus, get_status, ServiceStatus};
+use crate::user::User;
+
+
+const DEFAULT_AUDIT_SMS_ENTRIES: usize = 5;
+const MAX_AUDIT_SMS_ENTRIES: usize = 10;
+
+//the router is neither rebooted nor shut down by tests
+#[cfg(not(test))]
+const REBOOT_COMMAND: &str = "reboot";
+#[cfg(test)]
+const REBOOT_COMMAND: &str = "true";
+#[cfg(not(test))]
+const SHUTDOWN_COMMAND: &str = "poweroff";
+#[cfg(test)]
+const SHUTDOWN_COMMAND: &str = "true";
+
+///Channel the request was received through
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RequestOrigin {
+    Sms,
+    //request configured for the caller, which cannot reply nor add a code
+    Call,
+}
 
+///Returns the message to be returned to the request sender as acknowledgement,
+/// `sending_date` being the date the request was sent at, the request and its outcome being recorded in the audit trail
+pub async fn handle_request(sender: &str, request: &str, origin: RequestOrigin, sending_date: SystemTime, context: &mut Context) -> common::Result<String> {
+    let command = request.split_whitespace().next().unwrap_or_default();
+    let mut audit_entry = AuditEntry::new(sender, command);
+    //expired guests are removed before being able to run the request
+    context.clean_up_expired_guests().await;
+    let result = run_request(sender, request, origin, sending_date, context, &mut audit_entry).await;
+    audit_entry.outcome = match &result {
+        Ok(_) => "done".to_string(),
+        Err(e) => e.to_string(),
+    };
+    context.audit_log.append(&audit_entry);
+    if let Err(Error::SenderNotAllowed(_)) = &result {
+        context.record_unknown_sender(sender, request);
+    }
+    result
+}
 
-///Returns the message to be returned to the request sender as acknowledgement
-pub async fn handle_request(sender: &str, request: &str, context: &mut Context) -> common::Result<String> {
+async fn run_request(sender: &str, request: &str, origin: RequestOrigin, sending_date: SystemTime, context: &mut Context, audit_entry: &mut AuditEntry) -> common::Result<String> {
     info!("handle_request - request received - sender {:?} - request {:?}",sender,request);
 
-    //check if allowed user
-    let user = context.configuration.users.iter().filter(|user| { user.phone_number == sender }).next().ok_or_else(|| {
-        error!("handle_request - sender is not allowed");
-        Error::SenderNotAllowed(sender.to_string())
-    })?;
+    //check rate limits and lockout before rejecting unknown senders, so that their requests count within the global window
+    let rate_limit_config = context.configuration.rate_limit_config.clone().unwrap_or_default();
+    let known_user = find_user(sender, &context.configuration);
+    let rate_limit_key = known_user.as_ref().map(|user| user.name.clone()).unwrap_or_else(|_| sender.to_string());
+    let rate_limit_result = context.rate_limiter.check_request(&rate_limit_config, &rate_limit_key, SystemTime::now());
+
+    //check if allowed user, unknown senders never being answered
+    let user = known_user?;
+    audit_entry.user = Some(user.name.clone());
+    rate_limit_result?;
 
     info!("handle_request - sms received from allowed sender {}",user.name);
 
+    //check request freshness, sms may be delayed by the network or stored while the daemon was not running
+    let max_age = Duration::from_secs(context.configuration.sms_config.sms_max_age_sec.unwrap_or(sms_utils::DEFAULT_SMS_MAX_AGE_SEC));
+    if SystemTime::now().duration_since(sending_date).map(|age| age > max_age).unwrap_or(false) {
+        let sending_date = humantime::format_rfc3339_seconds(sending_date);
+        error!("handle_request - request sent at {} is too old",sending_date);
+        return Err(Error::InvalidRequest(format!("It was sent at {}, too long ago to be processed", sending_date)));
+    }
+
     //check request content
-    let mut args = request.split_whitespace();
-    let command = args.next().ok_or_else(|| {
+    let confirmed_request: String;
+    let mut words: Vec<&str> = request.split_whitespace().collect();
+    let mut command = *words.first().ok_or_else(|| {
         error!("handle_request - cannot read command from request");
-        Error::InvalidRequestError(format!("Cannot read command from request: {}", request))
+        Error::InvalidRequest(format!("Cannot read command from request: {}", request))
     })?;
 
+    //check user permissions, confirmations being only accepted for requests already checked
+    if !command.eq_ignore_ascii_case("confirm") && !user.is_command_allowed(command) {
+        error!("handle_request - command {} not allowed for {}",command,user.name);
+        return Err(Error::CommandNotAllowed(format!("you are not allowed to run the {} command", command.to_lowercase())));
+    }
+
+    //check code, appended as the last word of sensitive commands
+    if auth::is_code_required(user, command) {
+        let code = if words.len() > 1 && words.last().map(|word| auth::is_code_shaped(user, word)).unwrap_or(false) { words.pop() } else { None };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let last_accepted_step = context.totp_steps.last_accepted(&user.name);
+        let accepted_step = match auth::check_code(user, code, now, last_accepted_step) {
+            Ok(accepted_step) => accepted_step,
+            Err(e) => {
+                //calls cannot be answered with a code, their failures do not lead to a lockout
+                if origin == RequestOrigin::Sms && context.rate_limiter.record_authentication_failure(&rate_limit_config, &user.name, SystemTime::now()) {
+                    let lockout_minutes = rate_limit::minutes(rate_limit_config.lockout_duration());
+                    let name = user.name.clone();
+                    context.alert_admins(&format!("User {} is locked out for {} minutes after repeated authentication failures", name, lockout_minutes)).await;
+                    return Err(Error::Authentication(format!("too many authentication failures, your requests are blocked for {} minutes", lockout_minutes)));
+                }
+                return Err(e);
+            }
+        };
+        if let Some(step) = accepted_step {
+            context.totp_steps.accept(&user.name, step);
+        }
+        info!("handle_request - code checked for {}",user.name);
+    }
+    audit_entry.arguments = words.iter().skip(1).map(|word| word.to_string()).collect();
+
+    //check confirmation, if enabled destructive requests are run only once confirmed,
+    // except calls which cannot be mistyped nor forwarded
+    if command.eq_ignore_ascii_case("confirm") {
+        confirmed_request = context.pending_actions.confirm(&user.name, words.get(1).copied(), SystemTime::now())?;
+        info!("handle_request - running confirmed request {:?}",confirmed_request);
+        audit_entry.arguments = confirmed_request.split_whitespace().map(|word| word.to_string()).collect();
+        words = confirmed_request.split_whitespace().collect();
+        command = words[0];
+    } else if let Some(confirmation_config) = context.configuration.confirmation_config.clone().filter(|config| origin == RequestOrigin::Sms && config.is_confirmation_required(command)) {
+        let pending_request = words.join(" ");
+        let code = context.pending_actions.add(&confirmation_config, &user.name, &pending_request, SystemTime::now());
+        return Ok(format!("Reply \"confirm {}\" within {} seconds to run: {}", code, confirmation_config.window().as_secs(), pending_request));
+    }
+    let mut args = words.into_iter().skip(1);
+
     match command.to_lowercase().as_str() {
         "open" => {
             info!("handle_request - opening tunnel");
@@ -35,11 +138,16 @@ pub async fn handle_request(sender: &str, request: &str, context: &mut Context)
             //reading application to open the tunnel
             let application_str = args.next().ok_or_else(|| {
                 error!("handle_request - no application specified");
-                Error::InvalidRequestError(format!("No application specified: {}", request))
+                Error::InvalidRequest(format!("No application specified: {}", request))
             })?;
 
             info!("handle_request - requested application: {}",application_str);
 
+            if !user.is_application_allowed(application_str) {
+                error!("handle_request - cannot open tunnel: application {} not allowed for {}",application_str,user.name);
+                return Err(Error::CommandNotAllowed(format!("you are not allowed to access {}", application_str)));
+            }
+
             //checking if the current status allows tunnel opening
             if !matches!(context.status.device_status,DeviceStatus::Ready) {
                 error!("handle_request - cannot open tunnel: device status: {:?}",context.status.device_status);
@@ -47,19 +155,18 @@ pub async fn handle_request(sender: &str, request: &str, context: &mut Context)
             }
             if !matches!(context.status.email_service_status,ServiceStatus::Reachable) {
                 error!("handle_request - cannot open tunnel: email service is not reachable");
-                return Err(Error::InvalidStatus(format!("Email service is not reachable")));
+                return Err(Error::InvalidStatus("Email service is not reachable".to_string()));
             }
             if !matches!(context.status.ssh_tunnel_service_status,ServiceStatus::Reachable) {
                 error!("handle_request - cannot open tunnel: ssh tunnel service is not reachable");
-                return Err(Error::InvalidStatus(format!("SSH tunnel service is not reachable")));
+                return Err(Error::InvalidStatus("SSH tunnel service is not reachable".to_string()));
             }
 
             //resolve application
-            let application = context.configuration.applications.iter().filter(|app| { app.name == application_str }).next()
-                .ok_or(Error::InvalidRequestError(format!("Unknown application: {}", application_str)))
-                .or_else(|e| {
+            let application = context.configuration.applications.iter().find(|app| { app.name == application_str })
+                .ok_or_else(|| {
                     error!("handle_request - cannot open tunnel: application {} is unknown",application_str);
-                    Err(e)
+                    Error::InvalidRequest(format!("Unknown application: {}", application_str))
                 })?;
             if !matches!(context.status.applications_status.get(application_str).unwrap_or(&ServiceStatus::Unreachable),ServiceStatus::Reachable) {
                 error!("handle_request - cannot open tunnel: application {} is not reachable",application_str);
@@ -83,7 +190,7 @@ pub async fn handle_request(sender: &str, request: &str, context: &mut Context)
                 tunnel.user == user.name && tunnel.application == application.name
             }){
                 error!("handle_request - a tunnel is already open by the user for this application");
-                return Err(Error::InvalidRequestError(format!("A tunnel is already open for this application: {}",*tunnel_ref)));
+                return Err(Error::InvalidRequest(format!("A tunnel is already open for this application: {}",*tunnel_ref)));
             }
 
             //open ssh tunnel towards this app
@@ -102,7 +209,11 @@ pub async fn handle_request(sender: &str, request: &str, context: &mut Context)
             info!("handle_request - tunnel url sent by mail to: {}",user.email);
 
             let process_id = context.tunnels.len() as u32;
-            context.tunnels.insert(process_id, Tunnel::new(user.name.clone(), application.name.clone(), tunnel_process));
+            //user's tunnel duration cannot exceed the global one
+            let global_max_duration = context.configuration.ssh_config.tunnel_timeout_sec;
+            let max_duration = Duration::from_secs(user.tunnel_max_duration_sec.map(|d| d.min(global_max_duration)).unwrap_or(global_max_duration));
+            audit_entry.tunnels.push(process_id);
+            context.tunnels.insert(process_id, Tunnel::new(user.name.clone(), application.name.clone(), tunnel_process, max_duration));
 
             //todo indicate the mail in the ack, but masking it
             Ok(format!("Tunnel has been setup, reference is: {}\nAccess url has been send to you by mail", process_id))
@@ -116,7 +227,7 @@ pub async fn handle_request(sender: &str, request: &str, context: &mut Context)
                 //parsing tunnel process reference to int
                 let reference: u32 = s.parse::<u32>().map_err(|_| {
                     error!("handle_request - invalid tunnel reference");
-                    Error::InvalidRequestError(format!("Invalid tunnel reference: {}", s))
+                    Error::InvalidRequest(format!("Invalid tunnel reference: {}", s))
                 })?;
                 vec!(reference)
             } else {
@@ -131,23 +242,34 @@ pub async fn handle_request(sender: &str, request: &str, context: &mut Context)
                 }).collect();
                 if refs.is_empty() {
                     error!("handle_request - no tunnel reference found");
-                    Err(Error::InvalidRequestError(format!("No open tunnel")))
+                    Err(Error::InvalidRequest("No open tunnel".to_string()))
                 } else {
                     Ok(refs)
                 }?
             };
 
             info!("handle_request - closing tunnel(s) with reference(s): {:?}",references);
+            audit_entry.tunnels = references.clone();
 
-            //resolve process
+            //check every tunnel before closing any of them, only admins closing the tunnels of others
             for reference in &references {
-                let mut entry = context.tunnels.remove(&reference).ok_or_else(|| {
-                    error!("handle_request - unknown application");
-                    Error::InvalidRequestError(format!("Unknown tunnel reference: {}", reference))
+                let tunnel = context.tunnels.get(reference).ok_or_else(|| {
+                    error!("handle_request - unknown tunnel reference");
+                    Error::InvalidRequest(format!("Unknown tunnel reference: {}", reference))
                 })?;
-                //killing it
-                entry.process.kill().await?;
-                info!("handle_request - tunnel process with reference: {} has been killed",reference);
+                if !user.is_admin() && (tunnel.user != user.name || !user.is_application_allowed(&tunnel.application)) {
+                    error!("handle_request - cannot close tunnel {} of {} to {}: not allowed for {}",reference,tunnel.user,tunnel.application,user.name);
+                    return Err(Error::CommandNotAllowed(format!("you are not allowed to close tunnel {}", reference)));
+                }
+            }
+
+            //resolve process
+            for reference in &references {
+                if let Some(mut entry) = context.tunnels.remove(reference) {
+                    //killing it
+                    entry.process.kill().await?;
+                    info!("handle_request - tunnel process with reference: {} has been killed",reference);
+                }
             }
 
             let message = if references.len() > 1 {
@@ -162,23 +284,117 @@ pub async fn handle_request(sender: &str, request: &str, context: &mut Context)
             info!("handle_request - resolve status");
             let status = get_status(&context.configuration).await?;
 
-            let status_printed = status.to_string();
+            let status_printed = status.to_string_for(user);
             //updating available applications with the latest status
             context.update_status(status);
 
             Ok(status_printed)
         }
 
+        "balance" => {
+            info!("handle_request - balance");
+            let ussd_config = context.configuration.ussd_config.as_ref().ok_or_else(|| {
+                error!("handle_request - no ussd configuration");
+                Error::InvalidRequest("Balance query is not configured".to_string())
+            })?;
+            ussd_utils::send_ussd(context.modem.as_ref(), &ussd_config.balance_code).await
+        }
+
+        "audit" => {
+            info!("handle_request - audit");
+            if !user.is_admin() {
+                error!("handle_request - audit trail requested by non admin user {}",user.name);
+                return Err(Error::CommandNotAllowed("only admins can read the audit trail".to_string()));
+            }
+            let count = match args.next() {
+                Some(s) => s.parse::<usize>().map_err(|_| {
+                    error!("handle_request - invalid audit entry number");
+                    Error::InvalidRequest(format!("Invalid entry number: {}", s))
+                })?,
+                None => DEFAULT_AUDIT_SMS_ENTRIES,
+            };
+            let entries = context.audit_log.latest(count.min(MAX_AUDIT_SMS_ENTRIES));
+            if entries.is_empty() {
+                return Ok("No request recorded".to_string());
+            }
+            Ok(entries.iter().map(|entry| entry.to_string()).collect::<Vec<String>>().join("\n"))
+        }
+
+        "invite" => {
+            info!("handle_request - invite");
+            if !user.is_admin() {
+                error!("handle_request - invitation requested by non admin user {}",user.name);
+                return Err(Error::CommandNotAllowed("only admins can invite guests".to_string()));
+            }
+            let (Some(phone_number), Some(email), Some(application), Some(duration)) = (args.next(), args.next(), args.next(), args.next()) else {
+                error!("handle_request - missing invitation parameters");
+                return Err(Error::InvalidRequest("Usage: invite <phone> <email> <application> <duration>".to_string()));
+            };
+            if !email.contains('@') {
+                error!("handle_request - invalid guest email: {}",email);
+                return Err(Error::InvalidRequest(format!("Invalid email: {}", email)));
+            }
+            let duration = humantime::parse_duration(duration).map_err(|_| {
+                error!("handle_request - invalid invitation duration: {}",duration);
+                Error::InvalidRequest(format!("Invalid duration: {}, such as 30m or 4h expected", duration))
+            })?;
+            if !context.configuration.applications.iter().any(|app| app.name == application) {
+                error!("handle_request - cannot invite guest: application {} is unknown",application);
+                return Err(Error::InvalidRequest(format!("Unknown application: {}", application)));
+            }
+            //normalized so that a guest is identified whatever the format of the number
+            let phone_number = &sms_utils::normalize_phone_number(phone_number, context.configuration.sms_config.default_country_code.as_deref());
+            if let Ok(existing_user) = find_user(phone_number, &context.configuration) {
+                if context.guests.guests.iter().all(|guest| guest.name() != existing_user.name) {
+                    error!("handle_request - cannot invite {}: already a user",phone_number);
+                    return Err(Error::InvalidRequest(format!("{} is already a user: {}", phone_number, existing_user.name)));
+                }
+            }
+
+            let guest = Guest::new(phone_number, email, application, &user.name, duration);
+            let expiration_date = humantime::format_rfc3339_seconds(guest.expiration_date());
+            let invitation = OutgoingSms {
+                to: phone_number.to_string(),
+                msg: format!("Hello! {} invited you to access {} until {}, send \"open {}\" to this number to receive the access url by mail", user.name, application, expiration_date, application),
+            };
+            context.add_guest(guest);
+            if let Err(e) = context.send_sms(invitation).await {
+                error!("handle_request - cannot send invitation - error: {:?}",e);
+            }
+            Ok(format!("{} has been invited to access {} until {}", phone_number, application, expiration_date))
+        }
+
+        "revoke" => {
+            info!("handle_request - revoke");
+            if !user.is_admin() {
+                error!("handle_request - revocation requested by non admin user {}",user.name);
+                return Err(Error::CommandNotAllowed("only admins can revoke guests".to_string()));
+            }
+            let phone_number = args.next().ok_or_else(|| {
+                error!("handle_request - no guest specified");
+                Error::InvalidRequest("Usage: revoke <phone>".to_string())
+            })?;
+            let name = find_user(phone_number, &context.configuration).map(|guest| guest.name.clone()).map_err(|_| {
+                error!("handle_request - cannot revoke {}: unknown number",phone_number);
+                Error::InvalidRequest(format!("{} is not a guest", phone_number))
+            })?;
+            context.revoke_guest(&name).await.ok_or_else(|| {
+                error!("handle_request - cannot revoke {}: not a guest",phone_number);
+                Error::InvalidRequest(format!("{} is not a guest", phone_number))
+            })?;
+            Ok(format!("Access of {} has been revoked", phone_number))
+        }
+
         "reboot" => {
             info!("handle_request - reboot");
 
-            init::register_init_listener(&user);
-            let _ = tokio::spawn(
+            init::register_init_listener(user);
+            tokio::spawn(
                 async move {
                     //delay before rebooting so that answer can be returned to sender
                     info!("handle_request - rebooting in 5 secs");
                     tokio::time::sleep(Duration::from_secs(5)).await;
-                    _ = Command::new("reboot")
+                    _ = Command::new(REBOOT_COMMAND)
                         .spawn()
                 }
             );
@@ -188,12 +404,12 @@ pub async fn handle_request(sender: &str, request: &str, context: &mut Context)
         "shutdown" => {
             info!("handle_request - shutdown");
 
-            let _ = tokio::spawn(
+            tokio::spawn(
                 async move {
                     //delay before rebooting so that answer can be returned to sender
                     info!("handle_request - shutingdown in 5 secs");
                     tokio::time::sleep(Duration::from_secs(5)).await;
-                    _ = Command::new("poweroff")
+                    _ = Command::new(SHUTDOWN_COMMAND)
                         .spawn()
                 }
             );
@@ -202,7 +418,19 @@ pub async fn handle_request(sender: &str, request: &str, context: &mut Context)
 
         _ => {
             error!("handle_request - unknown command: {:?}", command);
-            Err(Error::InvalidRequestError(format!("Unknown command: {}", command)))
+            Err(Error::InvalidRequest(format!("Unknown command: {}", command)))
         }
     }
 }
+
+///Returns the user whose phone number is the sender one
+pub fn find_user<'a>(sender: &str, configuration: &'a Configuration) -> common::Result<&'a User> {
+    let default_country_code = configuration.sms_config.default_country_code.as_deref();
+    let sender_number = sms_utils::normalize_phone_number(sender, default_country_code);
+    configuration.users.iter().find(|user| {
+        sms_utils::normalize_phone_number(&user.phone_number, default_country_code) == sender_number
+    }).ok_or_else(|| {
+        error!("find_user - sender is not allowed");
+        Error::SenderNotAllowed(sender.to_string())
+    })
+}
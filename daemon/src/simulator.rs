@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use gsm7::Gsm7Writer;
+use serial2::SerialPort;
+use crate::pdu;
+
+const READ_TIMEOUT_MS: u64 = 50;
+const CTRL_Z: char = '\x1A';
+//uqmi -d <directory> <command> [<arguments>], messages being stored as files in <directory>/messages
+const UQMI_SCRIPT: &str = r#"#!/bin/sh
+directory="$2"
+shift 2
+case "$1" in
+    --list-messages)
+        printf '[%s]\n' "$(ls "$directory/messages" | sort -n | paste -s -d, -)" ;;
+    --get-message)
+        cat "$directory/messages/$2" ;;
+    --delete-message)
+        rm "$directory/messages/$2" ;;
+    --send-message)
+        printf '%s\t%s\n' "$4" "$2" >> "$directory/sent" ;;
+    *)
+        exit 1 ;;
+esac
+"#;
+
+static UQMI_SIMULATOR_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+///Modem simulator speaking AT over a pseudo-terminal, for end-to-end tests,
+/// the daemon opens `device` as it would open the modem serial port
+pub struct ModemSimulator {
+    pub device: String,
+    state: Arc<Mutex<SimulatorState>>,
+    writer: Arc<Mutex<SerialPort>>,
+    stopped: Arc<AtomicBool>,
+    //keeps the pseudo-terminal alive until the daemon opens it
+    _device_port: SerialPort,
+}
+
+#[derive(Default)]
+struct SimulatorState {
+    commands: Vec<String>,
+    sent_pdus: Vec<String>,
+    stored_pdus: BTreeMap<u32, String>,
+    message_reference: u8,
+    sending_failure: bool,
+    //+CMGS response without message reference
+    message_reference_missing: bool,
+    //AT+CMGF and AT+CNMI parameters, none until set
+    message_format: Option<String>,
+    sms_indication: Option<String>,
+    //+CUSD result parameters
+    ussd_response: String,
+}
+
+impl ModemSimulator {
+    pub fn start() -> std::io::Result<Self> {
+        let (port, device_port) = SerialPort::pair()?;
+        let device = std::fs::read_link(format!("/proc/self/fd/{}", device_port.as_raw_fd()))?.to_string_lossy().to_string();
+        let mut reader = port.try_clone()?;
+        reader.set_read_timeout(Duration::from_millis(READ_TIMEOUT_MS))?;
+        let simulator = ModemSimulator {
+            device,
+            state: Arc::new(Mutex::new(SimulatorState::default())),
+            writer: Arc::new(Mutex::new(port)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            _device_port: device_port,
+        };
+        let (state, writer, stopped) = (simulator.state.clone(), simulator.writer.clone(), simulator.stopped.clone());
+        thread::spawn(move || run(reader, state, writer, stopped));
+        Ok(simulator)
+    }
+
+    ///Sends the sms as a +CMT unsolicited result, as in forward reception mode
+    pub fn inject_sms(&self, from: &str, message: &str) {
+        self.inject_sms_received_at(from, message, SystemTime::now());
+    }
+
+    ///Sends the sms as a +CMT unsolicited result, the service centre having received it at `date`
+    pub fn inject_sms_received_at(&self, from: &str, message: &str, date: SystemTime) {
+        let pdu = build_sms_deliver(from, message, date);
+        self.write(&format!("\r\n+CMT: ,{}\r\n{}\r\n", pdu.len() / 2 - 1, pdu));
+    }
+
+    ///Stores the sms and notifies its storage index as a +CMTI unsolicited result, as in store reception mode
+    pub fn store_sms(&self, from: &str, message: &str) {
+        let index = self.add_stored_sms(from, message);
+        self.notify_stored_sms(index);
+    }
+
+    ///Notifies a storage index as a +CMTI unsolicited result, whether an sms is stored there or not
+    pub fn notify_stored_sms(&self, index: u32) {
+        self.write(&format!("\r\n+CMTI: \"SM\",{}\r\n", index));
+    }
+
+    ///Stores the sms without notifying it, as if received while the daemon was not running
+    pub fn add_stored_sms(&self, from: &str, message: &str) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        let index = state.stored_pdus.keys().last().map(|index| index + 1).unwrap_or(0);
+        let _ = state.stored_pdus.insert(index, build_sms_deliver(from, message, SystemTime::now()));
+        index
+    }
+
+    ///Rings, the calling number being identified, until hung up
+    pub fn inject_call(&self, from: &str) {
+        self.write(&format!("\r\nRING\r\n\r\n+CLIP: \"{}\",145,,,,0\r\n", from));
+    }
+
+    pub fn inject_status_report(&self, pdu: &str) {
+        self.write(&format!("\r\n+CDS: {}\r\n{}\r\n", pdu.len() / 2 - 1, pdu));
+    }
+
+    ///Makes the network reject the sent pdus, as when no network is available
+    pub fn set_sending_failure(&self, sending_failure: bool) {
+        self.state.lock().unwrap().sending_failure = sending_failure;
+    }
+
+    ///Makes the modem answer sent pdus without their message reference
+    pub fn set_message_reference_missing(&self, message_reference_missing: bool) {
+        self.state.lock().unwrap().message_reference_missing = message_reference_missing;
+    }
+
+    ///Defines the network response to ussd codes, as +CUSD parameters such as `0,"Balance: 5 EUR",15`
+    pub fn set_ussd_response(&self, ussd_response: &str) {
+        self.state.lock().unwrap().ussd_response = ussd_response.to_string();
+    }
+
+    ///Loses the configuration, as when the modem restarts on its own
+    pub fn restart(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.message_format = None;
+        state.sms_indication = None;
+    }
+
+    pub fn commands(&self) -> Vec<String> {
+        self.state.lock().unwrap().commands.clone()
+    }
+
+    pub fn stored_indexes(&self) -> Vec<u32> {
+        self.state.lock().unwrap().stored_pdus.keys().copied().collect()
+    }
+
+    ///Returns the recipient and content of the sent messages, concatenated parts being reassembled
+    pub fn sent_messages(&self) -> Vec<(String, String)> {
+        let mut messages: Vec<(String, String)> = vec!();
+        for pdu in &self.state.lock().unwrap().sent_pdus {
+            let sms = parse_sms_submit(pdu);
+            let is_next_part = sms.user_data_header.as_ref().and_then(|header| header.concatenation())
+                .map(|header| header.sequence_number > 1)
+                .unwrap_or(false);
+            match messages.last_mut() {
+                Some((_, message)) if is_next_part => message.push_str(&sms.user_data),
+                _ => messages.push((sms.originating_address.value, sms.user_data)),
+            }
+        }
+        messages
+    }
+
+    ///Returns the content of each sent pdu, the parts of concatenated messages being returned separately
+    pub fn sent_parts(&self) -> Vec<String> {
+        self.state.lock().unwrap().sent_pdus.iter().map(|pdu| parse_sms_submit(pdu).user_data).collect()
+    }
+
+    fn write(&self, content: &str) {
+        self.writer.lock().unwrap().write_all(content.as_bytes()).unwrap();
+    }
+}
+
+impl Drop for ModemSimulator {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+fn run(reader: SerialPort, state: Arc<Mutex<SimulatorState>>, writer: Arc<Mutex<SerialPort>>, stopped: Arc<AtomicBool>) {
+    let mut content = String::new();
+    //AT+CMGS prompted for a pdu
+    let mut pending_pdu = false;
+    let mut buffer = [0; 256];
+    while !stopped.load(Ordering::Relaxed) {
+        match reader.read(&mut buffer) {
+            Ok(len) => content.push_str(&String::from_utf8_lossy(&buffer[..len])),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+            Err(_) => break,
+        }
+        let terminator = if pending_pdu { CTRL_Z } else { '\r' };
+        while let Some(position) = content.find(terminator) {
+            let input: String = content.drain(..=position).collect();
+            //echo
+            let mut response = input.clone();
+            let mut state = state.lock().unwrap();
+            if pending_pdu && state.sending_failure {
+                pending_pdu = false;
+                //unknown error
+                response.push_str("\r\n+CMS ERROR: 500\r\n");
+            } else if pending_pdu {
+                pending_pdu = false;
+                state.sent_pdus.push(input.trim_end_matches(CTRL_Z).to_string());
+                state.message_reference = state.message_reference.wrapping_add(1);
+                if state.message_reference_missing {
+                    response.push_str("\r\nOK\r\n");
+                } else {
+                    response.push_str(&format!("\r\n+CMGS: {}\r\n\r\nOK\r\n", state.message_reference));
+                }
+            } else {
+                let command = input.trim().to_string();
+                state.commands.push(command.clone());
+                response.push_str(&execute(&command, &mut state, &mut pending_pdu));
+            }
+            drop(state);
+            if writer.lock().unwrap().write_all(response.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn execute(command: &str, state: &mut SimulatorState, pending_pdu: &mut bool) -> String {
+    let parameter = |prefix: &str| command.strip_prefix(prefix).and_then(|index| index.parse::<u32>().ok());
+    if command == "AT" || command == "ATH" || command.starts_with("AT+CMEE=") || command.starts_with("AT+CLIP=") {
+        "\r\nOK\r\n".to_string()
+    } else if let Some(message_format) = command.strip_prefix("AT+CMGF=") {
+        state.message_format = Some(message_format.to_string());
+        "\r\nOK\r\n".to_string()
+    } else if let Some(sms_indication) = command.strip_prefix("AT+CNMI=") {
+        state.sms_indication = Some(sms_indication.to_string());
+        "\r\nOK\r\n".to_string()
+    } else if command == "AT+CMGF?" {
+        //text mode by default
+        format!("\r\n+CMGF: {}\r\n\r\nOK\r\n", state.message_format.as_deref().unwrap_or("1"))
+    } else if command == "AT+CNMI?" {
+        //unset parameters are reported as 0
+        let mut parameters: Vec<&str> = state.sms_indication.as_deref().map(|parameters| parameters.split(',').collect()).unwrap_or_default();
+        parameters.resize(5, "0");
+        format!("\r\n+CNMI: {}\r\n\r\nOK\r\n", parameters.join(","))
+    } else if command == "AT+CUSD=2" {
+        "\r\nOK\r\n".to_string()
+    } else if command.starts_with("AT+CUSD=1,") {
+        //the network response follows the final result code
+        format!("\r\nOK\r\n\r\n+CUSD: {}\r\n", state.ussd_response)
+    } else if command.starts_with("AT+CPMS=") {
+        let used = state.stored_pdus.len();
+        format!("\r\n+CPMS: {0},30,{0},30,{0},30\r\n\r\nOK\r\n", used)
+    } else if command.starts_with("AT+CMGS=") {
+        *pending_pdu = true;
+        "\r\n> ".to_string()
+    } else if let Some(index) = parameter("AT+CMGR=") {
+        match state.stored_pdus.get(&index) {
+            Some(pdu) => format!("\r\n+CMGR: 0,,{}\r\n{}\r\n\r\nOK\r\n", pdu.len() / 2 - 1, pdu),
+            None => "\r\n+CMS ERROR: 321\r\n".to_string(),
+        }
+    } else if command == "AT+CMGL=4" {
+        let mut response: String = state.stored_pdus.iter()
+            .map(|(index, pdu)| format!("\r\n+CMGL: {},0,,{}\r\n{}", index, pdu.len() / 2 - 1, pdu))
+            .collect();
+        response.push_str("\r\n\r\nOK\r\n");
+        response
+    } else if let Some(index) = parameter("AT+CMGD=") {
+        let _ = state.stored_pdus.remove(&index);
+        "\r\nOK\r\n".to_string()
+    } else {
+        "\r\nERROR\r\n".to_string()
+    }
+}
+
+///Builds a gsm 7-bit SMS-DELIVER pdu, without service centre address, timestamped with `date`
+fn build_sms_deliver(from: &str, message: &str, date: SystemTime) -> String {
+    //YYYY-MM-DDTHH:MM:SSZ
+    let date: Vec<char> = humantime::format_rfc3339_seconds(date).to_string().chars().filter(|c| c.is_ascii_digit()).skip(2).collect();
+    let timestamp: String = date.chunks(2).flat_map(|chunk| [chunk[1], chunk[0]]).chain("00".chars()).collect();
+    let digits = from.trim_start_matches('+');
+    let type_of_address = if from.starts_with('+') { 0x91 } else { 0x81 };
+    let padded_digits: Vec<char> = format!("{:F<width$}", digits, width = digits.len() + digits.len() % 2).chars().collect();
+    let swapped_digits: String = padded_digits.chunks(2).flat_map(|chunk| [chunk[1], chunk[0]]).collect();
+    let mut writer = Gsm7Writer::new(Vec::new());
+    writer.write_str(message).unwrap();
+    let user_data = hex::encode_upper(writer.into_writer().unwrap());
+    format!("0004{:02X}{:02X}{}0000{}{:02X}{}", digits.len(), type_of_address, swapped_digits, timestamp, pdu::Alphabet::Gsm7.message_len(message), user_data)
+}
+
+///Parses SMS-SUBMIT pdus by turning them into SMS-DELIVER ones, in any alphabet and with any user data header,
+/// the destination address being read as the originating one, the user data length being checked against the user data
+fn parse_sms_submit(pdu: &str) -> pdu::SmsDeliver {
+    let bytes = hex::decode(pdu).unwrap();
+    //service centre address, first octet, message reference
+    let service_centre_address_len = bytes[0] as usize;
+    let first_octet = bytes[1 + service_centre_address_len];
+    let address_start = 3 + service_centre_address_len;
+    //address length is expressed in digits
+    let address_end = address_start + 2 + (bytes[address_start] as usize + 1) / 2;
+    let validity_period_len = if first_octet & 0x18 == 0x10 { 1 } else { 0 };
+    let user_data_start = address_end + 2 + validity_period_len;
+    //user data length in septets for gsm 7-bit alphabet, in octets otherwise
+    let user_data_len = bytes[user_data_start] as usize;
+    let user_data_octets = match pdu::Alphabet::from_data_coding_scheme(bytes[address_end + 1]) {
+        pdu::Alphabet::Gsm7 => (user_data_len * 7 + 7) / 8,
+        _ => user_data_len,
+    };
+    assert_eq!(bytes.len() - user_data_start - 1, user_data_octets, "user data length mismatch in pdu {}", pdu);
+    assert!(user_data_octets <= pdu::SMS_MAX_OCTETS, "user data too long in pdu {}", pdu);
+    let mut deliver = vec!(0x00, first_octet & 0x40);
+    deliver.extend(&bytes[address_start..address_end + 2]);
+    //service centre timestamp: 2024-01-01 00:00:00
+    deliver.extend([0x42, 0x10, 0x10, 0, 0, 0, 0]);
+    deliver.extend(&bytes[user_data_start..]);
+    pdu::parse_sms_deliver(&hex::encode(deliver)).unwrap()
+}
+
+///Fake uqmi script handling sms commands, the daemon runs `binary` with `device` as qmi modem device
+pub struct UqmiSimulator {
+    pub binary: String,
+    pub device: String,
+}
+
+impl UqmiSimulator {
+    pub fn start() -> std::io::Result<Self> {
+        let directory = std::env::temp_dir().join(format!("telco-vecchio-uqmi-{}-{}", std::process::id(), UQMI_SIMULATOR_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(directory.join("messages"))?;
+        let binary = directory.join("uqmi");
+        std::fs::write(&binary, UQMI_SCRIPT)?;
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755))?;
+        Ok(UqmiSimulator {
+            binary: binary.to_string_lossy().to_string(),
+            device: directory.to_string_lossy().to_string(),
+        })
+    }
+
+    ///Stores the sms as reported by uqmi --get-message, `concatenation` being its reference, parts number and sequence number
+    pub fn store_sms(&self, id: u32, from: &str, message: &str, concatenation: Option<(u16, u8, u8)>) {
+        self.store_sms_with_timestamp(id, from, message, concatenation, &Self::timestamp(SystemTime::now()));
+    }
+
+    ///Stores the sms with the given uqmi timestamp, such as `2024-01-01 00:00:00` or `2024-01-01 00:00:00+02:00`
+    pub fn store_sms_with_timestamp(&self, id: u32, from: &str, message: &str, concatenation: Option<(u16, u8, u8)>, timestamp: &str) {
+        let concatenation = concatenation
+            .map(|(reference, parts_number, sequence_number)| format!(r#","concat_ref":{},"concat_parts":{},"concat_part":{}"#, reference, parts_number, sequence_number))
+            .unwrap_or_default();
+        let content = format!(r#"{{"smsc":"+33609001390","sender":"{}","timestamp":"{}","text":"{}"{}}}"#, from, timestamp, message, concatenation);
+        std::fs::write(self.directory().join("messages").join(id.to_string()), content).unwrap();
+    }
+
+    ///Formats the date as uqmi does, `YYYY-MM-DD HH:MM:SS`
+    pub fn timestamp(date: SystemTime) -> String {
+        humantime::format_rfc3339_seconds(date).to_string().replace('T', " ").trim_end_matches('Z').to_string()
+    }
+
+    pub fn stored_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = std::fs::read_dir(self.directory().join("messages")).unwrap()
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    ///Returns the recipient and content of the sent messages
+    pub fn sent_messages(&self) -> Vec<(String, String)> {
+        std::fs::read_to_string(self.directory().join("sent")).unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(to, message)| (to.to_string(), message.to_string()))
+            .collect()
+    }
+
+    fn directory(&self) -> PathBuf {
+        PathBuf::from(&self.device)
+    }
+}
+
+impl Drop for UqmiSimulator {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(self.directory());
+    }
+}
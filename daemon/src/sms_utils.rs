@@ -1,17 +1,33 @@
+use std::collections::{BTreeMap, HashMap};
 use std::io;
-use std::time::Duration;
-use gsm7::{Gsm7Reader, Gsm7Writer};
-use hex::FromHex;
-use log::{debug, error};
-use regex_lite::Regex;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration, SystemTime};
+use gsm7::Gsm7Writer;
+use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
-use serial2_tokio::SerialPort;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use crate::common;
+use tokio::sync::mpsc::UnboundedReceiver;
+use crate::{common, pdu};
 use crate::common::Error;
-use crate::common::Error::{SmsInitError, SmsSendingError};
+use crate::common::Error::SmsSending;
+use crate::modem::{Modem, UnsolicitedResult};
+use crate::pdu::{Alphabet, CONCATENATED_SMS_FILL_BITS, CONCATENATED_SMS_HEADER_SEPTETS, ConcatenationHeader, SmsStatusReport, Timestamp};
+use crate::status::QmiProvider;
 
 const SMS_VALIDITY_PERIOD: u8 = 1; //10 minutes
+const DEFAULT_SMS_MAX_PARTS: u8 = 5;
+const TRUNCATION_MARKER: &str = "...";
+const TYPE_OF_ADDRESS_UNKNOWN: u8 = 0x81;
+const TYPE_OF_ADDRESS_INTERNATIONAL: u8 = 0x91;
+const SHORT_CODE_MAX_LEN: usize = 6;
+const DEFAULT_SMS_CONCATENATION_TIMEOUT_SEC: u64 = 120;
+const DEFAULT_SMS_STORAGE: &str = "SM";
+const SMS_LIST_TIMEOUT_SEC: u64 = 30;
+const QMI_SMS_POLL_PERIOD_SEC: u64 = 5;
+pub const DEFAULT_SMS_MAX_AGE_SEC: u64 = 900;
+//bounds the received sms cache whatever the maximum sms age
+const RECEIVED_SMS_CACHE_MAX_LEN: usize = 100;
+
+static CONCATENATED_SMS_REFERENCE: AtomicU8 = AtomicU8::new(0);
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct SmsConfig {
@@ -20,190 +36,817 @@ pub struct SmsConfig {
     pub qmi_binary_file: String,
     pub sim_pin: String,
     pub sms_send_timeout_sec: u64,
+    pub sms_max_parts: Option<u8>,
+    pub sms_concatenation_timeout_sec: Option<u64>,
+    pub default_country_code: Option<String>,
+    pub sms_status_report: Option<bool>,
+    pub sms_delivery_max_retry: Option<u32>,
+    pub sms_reception_mode: Option<SmsReceptionMode>,
+    pub sms_storage: Option<String>,
+    pub sms_outbox_max_retry: Option<u32>,
+    pub sms_max_age_sec: Option<u64>,
+    pub sms_backend: Option<SmsBackend>,
+}
+
+///Forward: incoming sms are not stored, only forwarded on serial port, thus lost if not read on time,
+/// Store: incoming sms are stored on modem and read from the storage, then deleted once handled
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SmsReceptionMode {
+    #[default]
+    Forward,
+    Store,
 }
 
-pub async fn init(config: &SmsConfig) -> common::Result<()> {
-    let mut serial_port = open_serial_port(config).await.map_err(|e| {
-        error!("init: cannot open serial port - error: {:?}",e);
-        SmsInitError
+///At: sms are sent and received through AT commands on the modem serial port,
+/// Qmi: sms are sent and received through uqmi, leaving the modem serial port to other programs,
+/// incoming sms being polled from modem storage and deleted once handled
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SmsBackend {
+    #[default]
+    At,
+    Qmi,
+}
+
+pub async fn init(modem: &dyn Modem, config: &SmsConfig) -> common::Result<()> {
+    if config.sms_backend.unwrap_or_default() == SmsBackend::Qmi {
+        //no modem configuration, checking uqmi sms access
+        debug!("init: listing sms through uqmi");
+        let _ = QmiProvider::new(config).list_messages().await.map_err(|e| {
+            error!("uqmi --list-messages failed - error: {:?}",e);
+            e
+        })?;
+        debug!("init: success");
+        return Ok(());
+    }
+    //report errors as numeric +CME ERROR codes
+    debug!("init: running AT+CMEE");
+    let _ = modem.at_command("AT+CMEE=1").await.map_err(|e| {
+        error!("AT+CMEE failed - error: {:?}",e);
+        e
     })?;
+    //report the calling number after each RING, calls triggering the action configured for the caller
+    debug!("init: running AT+CLIP");
+    if let Err(e) = modem.at_command("AT+CLIP=1").await {
+        error!("AT+CLIP failed, calls cannot be identified - error: {:?}",e);
+    }
     //set mode to PDU mode
     debug!("init: running AT+CMGF");
-    let response = at_transaction(&mut serial_port, "AT+CMGF=0\r").await.map_err(|_| SmsInitError)?;
-    debug!("init: response received: {}",response);
-    if !response.contains("OK") {
-        error!("AT+CMGF failed - response: {}",response);
-        return Err(SmsInitError);
+    let _ = modem.at_command("AT+CMGF=0").await.map_err(|e| {
+        error!("AT+CMGF failed - error: {:?}",e);
+        e
+    })?;
+    let reception_mode = config.sms_reception_mode.unwrap_or_default();
+    if reception_mode == SmsReceptionMode::Store {
+        //defines the memory used to read, write and receive sms
+        debug!("init: running AT+CPMS");
+        let storage = config.sms_storage.as_deref().unwrap_or(DEFAULT_SMS_STORAGE);
+        let response = modem.at_command(format!("AT+CPMS=\"{0}\",\"{0}\",\"{0}\"", storage).as_str()).await.map_err(|e| {
+            error!("AT+CPMS failed - error: {:?}",e);
+            e
+        })?;
+        debug!("init: response received: {:?}",response);
     }
     debug!("init: running AT+CNMI");
-    //defines how new messages are indicated
-    //first int : defines how notifications are dispatched. Value : 2 -> send notifications to the TE, buffering them and sending them later if they cannot be sent.
-    //second int : defines how sms are stored. Value : 2 -> sms not stored on modem, simply forwarded on serial port
-    let response = at_transaction(&mut serial_port, "AT+CNMI=2,2\r").await.map_err(|_| SmsInitError)?;
-    debug!("init: response received: {}",response);
-    if !response.contains("OK") {
-        error!("AT+CNMI failed - response: {}",response);
-        return Err(SmsInitError);
-    }
+    let command = format!("AT+CNMI={}", sms_indication_parameters(config).iter().map(|parameter| parameter.to_string()).collect::<Vec<String>>().join(","));
+    let _ = modem.at_command(command.as_str()).await.map_err(|e| {
+        error!("AT+CNMI failed - error: {:?}",e);
+        e
+    })?;
     debug!("init: success");
     Ok(())
 }
 
-pub async fn send_sms(config: &SmsConfig, sms: &OutgoingSms) -> common::Result<()> {
-    tokio::time::timeout(Duration::from_secs(config.sms_send_timeout_sec),
-                         async {
-                             if sms.msg.len() > 140 {
-                                 error!("send_sms: sms message too long");
-                                 return Err(SmsSendingError);
-                             }
-                             let mut device_file = open_serial_port(config).await.map_err(|_| SmsSendingError)?;
-
-                             debug!("send_sms: building pdu");
-                             let encoded_number = encode_phone_number(&sms.to);
-                             let encoded_message = encode_message(&sms.msg).map_err(|_| SmsSendingError)?;
-                             let pdu = format!("0011000B91{}0000{:02X?}{:02X?}{}\x1A", encoded_number, SMS_VALIDITY_PERIOD, sms.msg.len() as u8, encoded_message); //len is specified in terms of septets
-                             debug!("send_sms : pdu built: {}",pdu);
-
-                             debug!("send_sms: running AT+CMGS");
-                             let response = at_transaction(&mut device_file, format!("AT+CMGS={}\r", (pdu.len() - 2) / 2).as_str()).await.map_err(|_| SmsSendingError)?;
-                             debug!("send_sms: response received: {}",response);
-                             if !response.contains(">") {
-                                 error!("AT+CMGS initiation failed - response: {}",response);
-                                 return Err(SmsSendingError);
-                             }
-                             debug!("AT+CMGS initiation success - sending command");
-                             let response = at_transaction(&mut device_file, pdu.as_str()).await.map_err(|_| SmsSendingError)?;
-                             debug!("send_sms: response received: {}",response);
-                             if !response.contains("OK") {
-                                 error!("AT+CMGS command failed - response: {}",response);
-                                 return Err(SmsSendingError);
-                             }
-                             debug!("send_sms: sms sent");
-                             Ok(())
-                         }).await
-        .unwrap_or(Err(SmsSendingError))
-}
-
-pub async fn wait_sms(config: &SmsConfig) -> common::Result<IncomingSms> {
-    let mut device_file = open_serial_port(config).await.map_err(|_| Error::SmsReadingError)?;
-
-    let re = Regex::new(r#"\+CMT:.*\r\n(.+)\r\n"#).unwrap();
-    debug!("wait_sms: waiting CMT response");
-    let pdu = loop {
-        let response = at_transaction(&mut device_file, "").await.map_err(|_| Error::SmsReadingError)?;
-        if !response.is_empty() {
-            debug!("wait_sms: content received: {}",response);
-            if let Some(capture) = re.captures(&response) {
-                debug!("wait_sms: CMT response received");
-                let [s] = capture.extract().1;
-                break s.to_string();
-            } else {
-                debug!("wait_sms: ignoring content");
+///Returns whether the configuration set by `init` is still applied,
+/// the modem losing it when restarting on its own
+pub async fn check_configuration(modem: &dyn Modem, config: &SmsConfig) -> common::Result<bool> {
+    if config.sms_backend.unwrap_or_default() == SmsBackend::Qmi {
+        return Ok(true);
+    }
+    //+CMGF: <mode>
+    let response = modem.at_command("AT+CMGF?").await?;
+    let message_format = read_query_response(&response, "+CMGF:");
+    if message_format != vec!(0) {
+        info!("check_configuration: unexpected message format: {:?}",response);
+        return Ok(false);
+    }
+    //+CNMI: <mode>,<mt>,<bm>,<ds>,<bfr>
+    let response = modem.at_command("AT+CNMI?").await?;
+    let sms_indication = read_query_response(&response, "+CNMI:");
+    if !sms_indication.starts_with(&sms_indication_parameters(config)) {
+        info!("check_configuration: unexpected sms indication: {:?}",response);
+        return Ok(false);
+    }
+    debug!("check_configuration: configuration applied");
+    Ok(true)
+}
+
+///Returns the AT+CNMI parameters defining how new messages are indicated
+fn sms_indication_parameters(config: &SmsConfig) -> Vec<u8> {
+    //first int : defines how notifications are dispatched. Value : 2 -> send notifications to the TE, buffering them and sending them later if they cannot be sent.
+    //second int : defines how sms are stored. Value : 2 -> sms not stored on modem, simply forwarded on serial port, Value : 1 -> sms stored on modem, storage index forwarded on serial port
+    //fourth int : defines how status reports are indicated. Value : 1 -> status reports forwarded on serial port
+    let sms_indication = match config.sms_reception_mode.unwrap_or_default() {
+        SmsReceptionMode::Forward => 2,
+        SmsReceptionMode::Store => 1,
+    };
+    if config.sms_status_report.unwrap_or(false) {
+        vec!(2, sms_indication, 0, 1)
+    } else {
+        vec!(2, sms_indication)
+    }
+}
+
+///Reads the integer values of a read command response, such as `+CNMI: 2,2,0,0,0`
+fn read_query_response(response: &[String], prefix: &str) -> Vec<u8> {
+    response.iter()
+        .find_map(|line| line.strip_prefix(prefix))
+        .map(|values| values.split(',').filter_map(|value| value.trim().parse::<u8>().ok()).collect())
+        .unwrap_or_default()
+}
+
+///Returns the message references assigned by the modem to the sent parts, identifying their status reports,
+/// none if they are not all known, the delivery of the sms being not trackable then
+pub async fn send_sms(modem: &dyn Modem, config: &SmsConfig, sms: &OutgoingSms) -> common::Result<Option<Vec<u8>>> {
+    let max_parts = config.sms_max_parts.unwrap_or(DEFAULT_SMS_MAX_PARTS).max(1);
+    //transliteration is only worth it when it spares the use of ucs2
+    let transliterated_message = transliterate(&sms.msg);
+    let (message, alphabet) = match Alphabet::select(&transliterated_message) {
+        Alphabet::Gsm7 => (transliterated_message, Alphabet::Gsm7),
+        alphabet => (sms.msg.clone(), alphabet),
+    };
+    debug!("send_sms: message alphabet: {:?}",alphabet);
+    let parts = split_message(&message, alphabet, max_parts as usize);
+
+    if config.sms_backend.unwrap_or_default() == SmsBackend::Qmi {
+        //parts are sent as distinct sms, status reports are not available
+        let qmi_provider = QmiProvider::new(config);
+        for (index, part) in parts.iter().enumerate() {
+            qmi_provider.send_message(&sms.to, part).await.map_err(|e| {
+                error!("send_sms: cannot send part {}/{} through uqmi - error: {:?}", index + 1, parts.len(), e);
+                e
+            })?;
+        }
+        debug!("send_sms: sms sent through uqmi");
+        return Ok(None);
+    }
+
+    debug!("send_sms: building pdus");
+    let encoded_number = encode_phone_number(&sms.to, config.default_country_code.as_deref());
+    let reference = CONCATENATED_SMS_REFERENCE.fetch_add(1, Ordering::Relaxed);
+    let total = parts.len() as u8;
+    let mut message_references = vec!();
+    for (index, part) in parts.iter().enumerate() {
+        let (first_octet, udh) = if total > 1 {
+            //user data header: IEI concatenated sms 8-bit reference, reference, parts number, part sequence number
+            (0x51, format!("050003{:02X?}{:02X?}{:02X?}", reference, total, index + 1))
+        } else {
+            (0x11, String::new())
+        };
+        //status report request
+        let first_octet = if config.sms_status_report.unwrap_or(false) {
+            first_octet | 0x20
+        } else {
+            first_octet
+        };
+        let (len, encoded_message) = match alphabet {
+            Alphabet::Gsm7 => {
+                let (fill_bits, header_septets) = if total > 1 {
+                    (CONCATENATED_SMS_FILL_BITS, CONCATENATED_SMS_HEADER_SEPTETS)
+                } else {
+                    (0, 0)
+                };
+                //len is specified in terms of septets, including the header ones, extension table characters taking 2 septets
+                (header_septets + alphabet.message_len(part), encode_message(part, fill_bits).map_err(|_| SmsSending)?)
             }
+            _ => {
+                //len is specified in terms of octets, including the header ones
+                let encoded_message = encode_ucs2_message(part);
+                ((udh.len() + encoded_message.len()) / 2, encoded_message)
+            }
+        };
+        let pdu = format!("00{:02X?}00{}00{:02X?}{:02X?}{:02X?}{}{}", first_octet, encoded_number, alphabet.data_coding_scheme(), SMS_VALIDITY_PERIOD, len as u8, udh, encoded_message);
+        debug!("send_sms : pdu {}/{} built: {}", index + 1, total, pdu);
+        let message_reference = send_pdu(modem, &pdu, Duration::from_secs(config.sms_send_timeout_sec)).await.map_err(|e| {
+            error!("send_sms: cannot send part {}/{} - error: {:?}", index + 1, total, e);
+            e
+        })?;
+        message_references.push(message_reference);
+    }
+    debug!("send_sms: sms sent - message references: {:?}",message_references);
+    Ok(message_references.into_iter().collect())
+}
+
+///Returns the message reference assigned by the modem, none if it cannot be read from the response
+async fn send_pdu(modem: &dyn Modem, pdu: &str, timeout: Duration) -> common::Result<Option<u8>> {
+    debug!("send_pdu: running AT+CMGS");
+    //length excludes the service centre address octet
+    let response = modem.at_command_with_data(format!("AT+CMGS={}", pdu.len() / 2 - 1).as_str(), pdu, timeout).await?;
+    debug!("send_pdu: response received: {:?}",response);
+    //+CMGS: <mr>
+    let message_reference = response.iter()
+        .find_map(|line| line.strip_prefix("+CMGS:"))
+        .and_then(|mr| mr.trim().parse::<u8>().ok());
+    if message_reference.is_none() {
+        error!("send_pdu: cannot read message reference - response: {:?}",response);
+    }
+    Ok(message_reference)
+}
+
+///Splits message into parts fitting in a single sms each,
+/// messages requiring more than `max_parts` parts are truncated
+fn split_message(message: &str, alphabet: Alphabet, max_parts: usize) -> Vec<String> {
+    if alphabet.message_len(message) <= alphabet.max_len() {
+        return vec!(message.to_string());
+    }
+    let max_len = alphabet.concatenated_max_len();
+    let mut parts = vec!();
+    let mut part = String::new();
+    let mut part_len = 0;
+    for c in message.chars() {
+        let char_len = alphabet.char_len(c);
+        if part_len + char_len > max_len {
+            if parts.len() + 1 == max_parts {
+                error!("split_message: message too long, truncating it to {} parts", max_parts);
+                let marker_len = alphabet.message_len(TRUNCATION_MARKER);
+                while part_len + marker_len > max_len {
+                    part_len -= part.pop().map(|c| alphabet.char_len(c)).unwrap_or(part_len);
+                }
+                part.push_str(TRUNCATION_MARKER);
+                break;
+            }
+            parts.push(std::mem::take(&mut part));
+            part_len = 0;
         }
+        part.push(c);
+        part_len += char_len;
+    }
+    parts.push(part);
+    parts
+}
+
+///Waits for an incoming sms, for a status report about a sent one or for a call, hung up without being answered,
+/// in store reception mode the returned sms has to be deleted from modem storage once handled
+pub async fn wait_sms(modem: &dyn Modem, unsolicited_results: &mut UnboundedReceiver<UnsolicitedResult>, config: &SmsConfig, concatenated_sms_buffer: &mut ConcatenatedSmsBuffer) -> common::Result<IncomingMessage> {
+    let concatenation_timeout = Duration::from_secs(config.sms_concatenation_timeout_sec.unwrap_or(DEFAULT_SMS_CONCATENATION_TIMEOUT_SEC));
+    if config.sms_backend.unwrap_or_default() == SmsBackend::Qmi {
+        return wait_qmi_sms(config, concatenated_sms_buffer, concatenation_timeout).await;
+    }
+    loop {
+        debug!("wait_sms: waiting CMT, CMTI or CDS unsolicited result");
+        let (indication, pdu, storage_index) = match unsolicited_results.recv().await.ok_or(Error::SmsReading)? {
+            UnsolicitedResult::Sms(pdu) => ("CMT", pdu, None),
+            UnsolicitedResult::StatusReport(pdu) => ("CDS", pdu, None),
+            UnsolicitedResult::StoredSms(index) => {
+                debug!("wait_sms: sms stored at index {}",index);
+                match read_stored_sms(modem, index).await {
+                    Ok(pdu) => ("CMT", pdu, Some(index)),
+                    Err(e) => {
+                        //the sms may already have been read and deleted with the stored sms listing
+                        error!("wait_sms: ignoring sms stored at index {} which cannot be read - error: {:?}",index,e);
+                        continue;
+                    }
+                }
+            }
+            UnsolicitedResult::Ring => {
+                debug!("wait_sms: ring, waiting for calling number");
+                continue;
+            }
+            UnsolicitedResult::Call(number) => {
+                info!("wait_sms: call from {:?}, hanging up",number);
+                //rejecting the call before it is answered, so that it costs nothing
+                if let Err(e) = modem.at_command("ATH").await {
+                    error!("wait_sms: cannot hang up - error: {:?}",e);
+                }
+                return Ok(IncomingMessage::Call(number));
+            }
+        };
+        debug!("wait_sms: {} unsolicited result received",indication);
+        if indication == "CDS" {
+            debug!("wait_sms: parsing status report pdu");
+            match pdu::parse_sms_status_report(&pdu) {
+                Ok(report) => return Ok(IncomingMessage::StatusReport(report)),
+                Err(e) => {
+                    error!("wait_sms: ignoring malformed status report pdu - error: {:?}",e);
+                    continue;
+                }
+            }
+        }
+        remove_expired_concatenated_sms(modem, config, concatenated_sms_buffer).await;
+        match read_sms_pdu(&pdu, storage_index, concatenated_sms_buffer) {
+            Ok(Some(sms)) => return Ok(IncomingMessage::Sms(sms)),
+            Ok(None) => {}
+            Err(e) => {
+                error!("wait_sms: ignoring malformed pdu - error: {:?}",e);
+                if let Some(index) = storage_index {
+                    delete_stored_sms(modem, config, index).await;
+                }
+            }
+        }
+    }
+}
+
+///Drops the concatenated sms whose parts were not all received in time, deleting the received parts from modem storage
+pub async fn remove_expired_concatenated_sms(modem: &dyn Modem, config: &SmsConfig, concatenated_sms_buffer: &mut ConcatenatedSmsBuffer) {
+    let concatenation_timeout = Duration::from_secs(config.sms_concatenation_timeout_sec.unwrap_or(DEFAULT_SMS_CONCATENATION_TIMEOUT_SEC));
+    for index in concatenated_sms_buffer.remove_expired(concatenation_timeout) {
+        delete_stored_sms(modem, config, index).await;
+    }
+}
+
+///Polls the sms stored on the modem through uqmi, the parts of pending concatenated sms being skipped
+async fn wait_qmi_sms(config: &SmsConfig, concatenated_sms_buffer: &mut ConcatenatedSmsBuffer, concatenation_timeout: Duration) -> common::Result<IncomingMessage> {
+    let qmi_provider = QmiProvider::new(config);
+    loop {
+        debug!("wait_qmi_sms: polling stored sms");
+        for id in concatenated_sms_buffer.remove_expired(concatenation_timeout) {
+            delete_qmi_message(&qmi_provider, id).await;
+        }
+        let ids = qmi_provider.list_messages().await.unwrap_or_else(|e| {
+            error!("wait_qmi_sms: cannot list stored sms - error: {:?}",e);
+            vec!()
+        });
+        for id in ids {
+            if concatenated_sms_buffer.contains(id) {
+                continue;
+            }
+            match read_qmi_message(&qmi_provider, id, concatenated_sms_buffer).await {
+                Ok(Some(sms)) => return Ok(IncomingMessage::Sms(sms)),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("wait_qmi_sms: deleting unreadable sms {} - error: {:?}",id,e);
+                    delete_qmi_message(&qmi_provider, id).await;
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(QMI_SMS_POLL_PERIOD_SEC)).await;
+    }
+}
+
+async fn read_qmi_message(qmi_provider: &QmiProvider, id: u32, concatenated_sms_buffer: &mut ConcatenatedSmsBuffer) -> common::Result<Option<IncomingSms>> {
+    let message = qmi_provider.get_message(id).await?;
+    let sms = IncomingSms {
+        from: message.sender,
+        msg: message.text,
+        timestamp: message.timestamp,
+        //without timezone, the sms age is counted from the time it is first read
+        sending_date: message.sending_date.unwrap_or_else(SystemTime::now),
+        storage_indexes: vec!(id),
     };
-    debug!("wait_sms: parsing pdu");
-    let number_encoded = &pdu[22..34];
-    let [message_size_septets] = <[u8; 1]>::from_hex(&pdu[52..54]).unwrap();
-    debug!("wait_sms: message size {:?}",message_size_septets);
-    let message_encoded = &pdu[54..];
-    let number = decode_phone_number(number_encoded);
-    let message = decode_message(message_encoded, message_size_septets as usize).map_err(|_| Error::SmsReadingError)?;
-    debug!("wait_sms: sender number {:?}",number);
-    debug!("wait_sms: message content {:?}",message);
-    Ok(IncomingSms { from: number.to_string(), msg: message.to_string() })
+    Ok(reassemble_sms(sms, message.concatenation, concatenated_sms_buffer))
 }
 
+async fn delete_qmi_message(qmi_provider: &QmiProvider, id: u32) {
+    match qmi_provider.delete_message(id).await {
+        Ok(()) => {
+            debug!("delete_qmi_message: sms {} deleted",id);
+        }
+        Err(e) => {
+            error!("delete_qmi_message: cannot delete sms {} - error: {:?}",id,e);
+        }
+    }
+}
 
+///Reads the sms stored on modem before the daemon started listening to incoming ones,
+/// each returned sms has to be deleted from modem storage once handled
+pub async fn read_sms_backlog(modem: &dyn Modem, config: &SmsConfig, concatenated_sms_buffer: &mut ConcatenatedSmsBuffer) -> common::Result<Vec<IncomingSms>> {
+    if config.sms_backend.unwrap_or_default() == SmsBackend::Qmi {
+        let qmi_provider = QmiProvider::new(config);
+        let mut backlog = vec!();
+        for id in qmi_provider.list_messages().await? {
+            match read_qmi_message(&qmi_provider, id, concatenated_sms_buffer).await {
+                Ok(Some(sms)) => backlog.push(sms),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("read_sms_backlog: deleting unreadable sms {} - error: {:?}",id,e);
+                    delete_qmi_message(&qmi_provider, id).await;
+                }
+            }
+        }
+        info!("read_sms_backlog: {} stored sms to handle",backlog.len());
+        return Ok(backlog);
+    }
+    if config.sms_reception_mode.unwrap_or_default() != SmsReceptionMode::Store {
+        return Ok(vec!());
+    }
+    debug!("read_sms_backlog: running AT+CMGL");
+    //listing all messages, whatever their status
+    let response = modem.at_command_with_timeout("AT+CMGL=4", Duration::from_secs(SMS_LIST_TIMEOUT_SEC)).await.map_err(|e| {
+        error!("AT+CMGL failed - error: {:?}",e);
+        e
+    })?;
+    debug!("read_sms_backlog: response received: {:?}",response);
+    let mut backlog = vec!();
+    //+CMGL: <index>,<stat>,[<alpha>],<length> followed by the pdu line
+    for (header, pdu) in response.iter().zip(response.iter().skip(1)).filter(|(header, _)| header.starts_with("+CMGL:")) {
+        let Some(index) = header.trim_start_matches("+CMGL:").split(',').next().and_then(|index| index.trim().parse::<u32>().ok()) else {
+            error!("read_sms_backlog: cannot read storage index from {:?}",header);
+            continue;
+        };
+        //parts of pending concatenated sms are already read
+        if concatenated_sms_buffer.contains(index) {
+            continue;
+        }
+        match read_sms_pdu(pdu, Some(index), concatenated_sms_buffer) {
+            Ok(Some(sms)) => backlog.push(sms),
+            Ok(None) => {}
+            Err(e) => {
+                error!("read_sms_backlog: deleting malformed pdu - error: {:?}",e);
+                delete_stored_sms(modem, config, index).await;
+            }
+        }
+    }
+    info!("read_sms_backlog: {} stored sms to handle",backlog.len());
+    Ok(backlog)
+}
+
+///Deletes handled sms from modem storage
+pub async fn delete_sms(modem: &dyn Modem, config: &SmsConfig, sms: &IncomingSms) {
+    for index in &sms.storage_indexes {
+        delete_stored_sms(modem, config, *index).await;
+    }
+}
+
+async fn read_stored_sms(modem: &dyn Modem, index: u32) -> common::Result<String> {
+    debug!("read_stored_sms: running AT+CMGR");
+    let response = modem.at_command(format!("AT+CMGR={}", index).as_str()).await.map_err(|e| {
+        error!("AT+CMGR failed - error: {:?}",e);
+        e
+    })?;
+    debug!("read_stored_sms: response received: {:?}",response);
+    //+CMGR: <stat>,[<alpha>],<length> followed by the pdu line
+    match response.as_slice() {
+        [header, pdu, ..] if header.starts_with("+CMGR:") => Ok(pdu.to_string()),
+        _ => {
+            error!("read_stored_sms: no sms stored at index {} - response: {:?}",index,response);
+            Err(Error::SmsReading)
+        }
+    }
+}
+
+async fn delete_stored_sms(modem: &dyn Modem, config: &SmsConfig, index: u32) {
+    if config.sms_backend.unwrap_or_default() == SmsBackend::Qmi {
+        delete_qmi_message(&QmiProvider::new(config), index).await;
+        return;
+    }
+    debug!("delete_stored_sms: running AT+CMGD");
+    match modem.at_command(format!("AT+CMGD={}", index).as_str()).await {
+        Ok(_) => {
+            debug!("delete_stored_sms: sms stored at index {} deleted",index);
+        }
+        Err(e) => {
+            error!("delete_stored_sms: cannot delete sms stored at index {} - error: {:?}",index,e);
+        }
+    }
+}
+
+///Returns the received sms, or none if it is part of a concatenated sms not completely received yet
+fn read_sms_pdu(pdu: &str, storage_index: Option<u32>, concatenated_sms_buffer: &mut ConcatenatedSmsBuffer) -> common::Result<Option<IncomingSms>> {
+    debug!("read_sms_pdu: parsing pdu");
+    let sms_deliver = pdu::parse_sms_deliver(pdu)?;
+    let concatenation_header = sms_deliver.user_data_header.as_ref().and_then(|header| header.concatenation());
+    let sms = IncomingSms {
+        from: sms_deliver.originating_address.value,
+        msg: sms_deliver.user_data,
+        timestamp: sms_deliver.service_centre_timestamp,
+        sending_date: sms_deliver.service_centre_timestamp.to_system_time(),
+        storage_indexes: storage_index.into_iter().collect(),
+    };
+    let Some(sms) = reassemble_sms(sms, concatenation_header, concatenated_sms_buffer) else {
+        return Ok(None);
+    };
+    debug!("read_sms_pdu: sender number {:?}",sms.from);
+    debug!("read_sms_pdu: message content {:?}",sms.msg);
+    Ok(Some(sms))
+}
+
+///Returns the sms once all its parts are received, when concatenated
+fn reassemble_sms(sms: IncomingSms, concatenation_header: Option<ConcatenationHeader>, concatenated_sms_buffer: &mut ConcatenatedSmsBuffer) -> Option<IncomingSms> {
+    match concatenation_header {
+        None => Some(sms),
+        Some(header) => {
+            debug!("reassemble_sms: part {}/{} of concatenated sms {} received",header.sequence_number,header.parts_number,header.reference);
+            concatenated_sms_buffer.push(sms, header)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum IncomingMessage {
+    Sms(IncomingSms),
+    StatusReport(SmsStatusReport),
+    //calling number
+    Call(String),
+}
+
+///`timestamp` is the date the service centre received the sms, or its first received part,
+/// `sending_date` the same date unless the timestamp timezone is unknown, the date the sms was first read then,
+/// `storage_indexes` locate the sms, or its parts, in modem storage when received in store reception mode
 #[derive(Debug)]
 pub struct IncomingSms {
     pub from: String,
     pub msg: String,
+    pub timestamp: Timestamp,
+    pub sending_date: SystemTime,
+    pub storage_indexes: Vec<u32>,
 }
 
+///Sms recently handled, identified by sender, service centre timestamp and content,
+/// in order to ignore the ones the modem delivers twice
+#[derive(Default)]
+pub struct ReceivedSmsCache {
+    entries: Vec<(String, Timestamp, String, SystemTime)>,
+}
 
-#[derive(Debug)]
-pub struct OutgoingSms {
-    pub to: String,
-    pub msg: String,
+impl ReceivedSmsCache {
+    ///Returns whether the sms has already been received, recording it otherwise,
+    /// entries older than `max_age` are dropped, such sms being rejected anyway
+    pub fn is_duplicate(&mut self, sms: &IncomingSms, max_age: Duration) -> bool {
+        let now = SystemTime::now();
+        self.entries.retain(|(_, _, _, sending_date)| {
+            now.duration_since(*sending_date).map(|age| age <= max_age).unwrap_or(true)
+        });
+        if self.entries.iter().any(|(from, timestamp, msg, _)| *from == sms.from && *timestamp == sms.timestamp && *msg == sms.msg) {
+            return true;
+        }
+        if self.entries.len() >= RECEIVED_SMS_CACHE_MAX_LEN {
+            let _ = self.entries.remove(0);
+        }
+        self.entries.push((sms.from.clone(), sms.timestamp, sms.msg.clone(), sms.sending_date));
+        false
+    }
 }
 
-async fn open_serial_port(config: &SmsConfig) -> Result<SerialPort, io::Error> {
-    SerialPort::open(&config.modem_device, serial2::KeepSettings).map_err(|e| {
-        error!("get_device_file: error: {:?}",e);
-        e
-    })
+
+struct ConcatenatedSms {
+    parts: BTreeMap<u8, String>,
+    parts_number: u8,
+    storage_indexes: Vec<u32>,
+    timestamp: Timestamp,
+    sending_date: SystemTime,
+    first_reception_date: SystemTime,
 }
 
-async fn at_transaction(serial_port: &mut SerialPort, command: &str) -> Result<String, io::Error> {
-    if !command.is_empty() {
-        debug!("at_transaction: sending command: {:?}",command);
-        serial_port.write_all(command.as_bytes()).await?;
+///Holds the parts of concatenated sms received so far, identified by sender and reference number
+#[derive(Default)]
+pub struct ConcatenatedSmsBuffer {
+    pending: HashMap<(String, u16), ConcatenatedSms>,
+}
 
-        //reading the command just sent
-        let mut buffer = vec![0; command.as_bytes().len()];
-        loop {
-            let l = serial_port.read(&mut buffer).await?;
-            buffer = buffer[l..].to_vec();
-            if buffer.len() == 0 {
-                break;
-            }
+impl ConcatenatedSmsBuffer {
+    ///Returns the reassembled sms once all its parts are received
+    fn push(&mut self, part: IncomingSms, header: ConcatenationHeader) -> Option<IncomingSms> {
+        let key = (part.from.clone(), header.reference);
+        let concatenated_sms = self.pending.entry(key.clone()).or_insert_with(|| ConcatenatedSms {
+            parts: BTreeMap::new(),
+            parts_number: header.parts_number,
+            storage_indexes: vec!(),
+            timestamp: part.timestamp,
+            sending_date: part.sending_date,
+            first_reception_date: SystemTime::now(),
+        });
+        let _ = concatenated_sms.parts.insert(header.sequence_number, part.msg);
+        concatenated_sms.storage_indexes.extend(part.storage_indexes);
+        if concatenated_sms.parts.len() < concatenated_sms.parts_number as usize {
+            return None;
         }
+        let concatenated_sms = self.pending.remove(&key)?;
+        Some(IncomingSms {
+            from: part.from,
+            msg: concatenated_sms.parts.into_values().collect(),
+            timestamp: concatenated_sms.timestamp,
+            sending_date: concatenated_sms.sending_date,
+            storage_indexes: concatenated_sms.storage_indexes,
+        })
+    }
+
+    ///Returns whether the storage index is the one of a pending part
+    fn contains(&self, storage_index: u32) -> bool {
+        self.pending.values().any(|concatenated_sms| concatenated_sms.storage_indexes.contains(&storage_index))
+    }
 
-        debug!("at_transaction: waiting response");
+    ///Returns the modem storage indexes of the expired parts
+    fn remove_expired(&mut self, timeout: Duration) -> Vec<u32> {
+        let current_time = SystemTime::now();
+        let mut storage_indexes = vec!();
+        self.pending.retain(|(from, reference), concatenated_sms| {
+            let expired = current_time.duration_since(concatenated_sms.first_reception_date).unwrap_or_default() > timeout;
+            if expired {
+                error!("remove_expired: concatenated sms {} from {} expired, {}/{} parts received",reference,from,concatenated_sms.parts.len(),concatenated_sms.parts_number);
+                storage_indexes.extend(&concatenated_sms.storage_indexes);
+            }
+            !expired
+        });
+        storage_indexes
     }
-    let mut buffer: [u8; 128] = [0; 128];
-    let len = serial_port.read(&mut buffer).await?;
-    String::from_utf8(buffer[0..len].to_vec()).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
 }
 
-fn encode_phone_number(phone_number: &str) -> String {
+#[derive(Debug, Clone)]
+pub struct OutgoingSms {
+    pub to: String,
+    pub msg: String,
+}
+
+///Encodes phone number as a TP-DA address field: number of digits, type of address and swapped semi-octets,
+/// numbers other than short codes are normalized and sent as international ones when possible,
+/// the others being sent with an unknown type of number, as dialled
+fn encode_phone_number(phone_number: &str, default_country_code: Option<&str>) -> String {
     debug!("encode_phone_number: in: {}",phone_number);
-    //stripping + and right padding with F
-    let paded_number: Vec<char> = format!("{:F<12}", phone_number.replace("+", "")).chars().collect();
+    let number = strip_phone_number(phone_number);
+    let number = if number.len() > SHORT_CODE_MAX_LEN { normalize_phone_number(&number, default_country_code) } else { number };
+    let (type_of_address, digits) = match number.strip_prefix('+') {
+        Some(digits) => (TYPE_OF_ADDRESS_INTERNATIONAL, digits),
+        None => (TYPE_OF_ADDRESS_UNKNOWN, number.as_str()),
+    };
+    //right padding with F
+    let padded_number: Vec<char> = format!("{:F<width$}", digits, width = digits.len() + digits.len() % 2).chars().collect();
     //swap numbers 2 by to
-    let mut encoded_numer = vec!();
-    paded_number.chunks(2).for_each(|chunk| {
-        encoded_numer.push(chunk[1]);
-        encoded_numer.push(chunk[0]);
+    let mut encoded_number = vec!();
+    padded_number.chunks(2).for_each(|chunk| {
+        encoded_number.push(chunk[1]);
+        encoded_number.push(chunk[0]);
     });
-    let number: String = encoded_numer.iter().collect();
+    let number = format!("{:02X?}{:02X?}{}", digits.len(), type_of_address, encoded_number.iter().collect::<String>());
     debug!("encode_phone_number: out: {}",number);
     number
 }
 
-fn decode_phone_number(encoded_phone_number: &str) -> String {
-    debug!("decode_phone_number: in: {}",encoded_phone_number);
-    let numbers: Vec<char> = encoded_phone_number.chars().collect();
-    //swap numbers 2 by to
-    let mut swapped_numbers = vec!();
-    numbers.chunks(2).for_each(|chunk| {
-        swapped_numbers.push(chunk[1]);
-        swapped_numbers.push(chunk[0]);
-    });
-    let padded_number: String = swapped_numbers.iter().collect();
-    let number = format!("+{}", padded_number.replace("F", ""));
-    debug!("decode_phone_number: out: {}",number);
-    number
+///Returns the E.164 form of the phone number, national numbers being resolved with the default country code if configured,
+/// so that numbers written in different formats can be compared
+pub fn normalize_phone_number(phone_number: &str, default_country_code: Option<&str>) -> String {
+    let number = strip_phone_number(phone_number);
+    if number.starts_with('+') {
+        number
+    } else if let Some(international_number) = number.strip_prefix("00") {
+        format!("+{}", international_number)
+    } else if let (Some(national_number), Some(country_code)) = (number.strip_prefix('0'), default_country_code) {
+        format!("+{}{}", country_code.trim_start_matches('+'), national_number)
+    } else {
+        number
+    }
+}
+
+///Removes the separators commonly used when writing phone numbers
+fn strip_phone_number(phone_number: &str) -> String {
+    phone_number.chars().filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')')).collect()
 }
 
-fn encode_message(message: &str) -> Result<String, io::Error> {
+///Replaces the typographic punctuation missing from gsm 7-bit alphabet by its plain equivalent,
+/// other characters are kept as is
+fn transliterate(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    for c in message.chars() {
+        match c {
+            '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{2032}' | '`' => out.push('\''),
+            '\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{2033}' | '«' | '»' => out.push('"'),
+            '\u{2010}'..='\u{2015}' | '\u{2212}' => out.push('-'),
+            '\u{2026}' => out.push_str("..."),
+            '\u{00A0}' | '\u{2009}' | '\u{202F}' => out.push(' '),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+///`fill_bits` are the padding bits required to align message on a septet boundary after a user data header
+fn encode_message(message: &str, fill_bits: u8) -> Result<String, io::Error> {
     debug!("encode_message: in: {}",message);
     let mut writer = Gsm7Writer::new(Vec::new());
+    for _ in 0..fill_bits {
+        writer.write_bit(false)?;
+    }
     writer.write_str(message)?;
     let out = hex::encode(writer.into_writer()?).to_uppercase();
     debug!("encode_message: out: {}",out);
-    return Ok(out);
+    Ok(out)
 }
 
-
-fn decode_message(encoded_message: &str, size_septet: usize) -> Result<String, io::Error> {
-    debug!("decode_message: in: {}",encoded_message);
-    let v = hex::decode(encoded_message.to_lowercase()).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
-    let reader = Gsm7Reader::new(io::Cursor::new(&v));
-    let mut out = reader.collect::<io::Result<String>>()?;
-    out.truncate(size_septet);
-    debug!("decode_message: out: {}",out);
-    return Ok(out);
+fn encode_ucs2_message(message: &str) -> String {
+    debug!("encode_ucs2_message: in: {}",message);
+    let out: String = message.encode_utf16().map(|unit| format!("{:04X?}", unit)).collect();
+    debug!("encode_ucs2_message: out: {}",out);
+    out
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_message_is_not_split() {
+        let message = "a".repeat(160);
+        assert_eq!(split_message(&message, Alphabet::Gsm7, 5), vec!(message));
+    }
+
+    #[test]
+    fn long_message_is_split_in_concatenated_parts() {
+        let message = "a".repeat(306);
+        let parts = split_message(&message, Alphabet::Gsm7, 2);
+        assert_eq!(parts, vec!("a".repeat(153), "a".repeat(153)));
+    }
+
+    #[test]
+    fn message_exceeding_max_parts_is_truncated() {
+        let message = "a".repeat(500);
+        let parts = split_message(&message, Alphabet::Gsm7, 2);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0], "a".repeat(153));
+        assert_eq!(parts[1], format!("{}{}", "a".repeat(150), TRUNCATION_MARKER));
+
+        //extension characters take two septets, the marker still fits in the last part
+        let message = "€".repeat(300);
+        let parts = split_message(&message, Alphabet::Gsm7, 2);
+        assert_eq!(parts.len(), 2);
+        assert!(parts[1].ends_with(TRUNCATION_MARKER));
+        assert!(parts.iter().all(|part| Alphabet::Gsm7.message_len(part) <= Alphabet::Gsm7.concatenated_max_len()));
+        assert_eq!(parts[1], format!("{}{}", "€".repeat(75), TRUNCATION_MARKER));
+    }
+
+    #[test]
+    fn ucs2_message_is_encoded_in_utf16_code_units() {
+        assert_eq!(encode_ucs2_message("aé€"), "006100E920AC");
+        //characters outside the basic multilingual plane take a surrogate pair
+        assert_eq!(encode_ucs2_message("😀"), "D83DDE00");
+    }
+
+    #[test]
+    fn ucs2_message_is_split_in_utf16_code_units() {
+        let message = "ж".repeat(70);
+        assert_eq!(split_message(&message, Alphabet::Ucs2, 5), vec!(message));
+        let parts = split_message(&"ж".repeat(71), Alphabet::Ucs2, 5);
+        assert_eq!(parts, vec!("ж".repeat(67), "ж".repeat(4)));
+        //a surrogate pair is never split across parts
+        let parts = split_message(&format!("{}😀жжж", "ж".repeat(66)), Alphabet::Ucs2, 5);
+        assert_eq!(parts, vec!("ж".repeat(66), "😀жжж".to_string()));
+    }
+
+    fn part(msg: &str, storage_index: u32) -> IncomingSms {
+        IncomingSms {
+            from: "+33612345678".to_string(),
+            msg: msg.to_string(),
+            timestamp: Timestamp { year: 24, month: 1, day: 1, hour: 0, minute: 0, second: 0, timezone: 0 },
+            sending_date: SystemTime::now(),
+            storage_indexes: vec!(storage_index),
+        }
+    }
+
+    fn header(sequence_number: u8) -> ConcatenationHeader {
+        ConcatenationHeader { reference: 7, parts_number: 3, sequence_number }
+    }
+
+    #[test]
+    fn out_of_order_parts_are_reassembled() {
+        let mut buffer = ConcatenatedSmsBuffer::default();
+        assert!(buffer.push(part("lo ", 4), header(2)).is_none());
+        assert!(buffer.push(part("world", 5), header(3)).is_none());
+        assert!(buffer.contains(4));
+        let sms = buffer.push(part("Hel", 3), header(1)).unwrap();
+        assert_eq!(sms.msg, "Hello world");
+        assert_eq!(sms.storage_indexes, vec!(4, 5, 3));
+        assert!(!buffer.contains(4));
+    }
+
+    #[test]
+    fn incomplete_sms_expires() {
+        let mut buffer = ConcatenatedSmsBuffer::default();
+        assert!(buffer.push(part("Hel", 3), header(1)).is_none());
+        assert!(buffer.push(part("world", 5), header(3)).is_none());
+        assert!(buffer.remove_expired(Duration::from_secs(60)).is_empty());
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(buffer.remove_expired(Duration::ZERO), vec!(3, 5));
+        assert!(!buffer.contains(3));
+        //a late part starts a new sms
+        assert!(buffer.push(part("lo ", 4), header(2)).is_none());
+    }
+
+    #[test]
+    fn phone_numbers_are_stripped() {
+        assert_eq!(strip_phone_number("+33 6 12-34.56 (78)"), "+33612345678");
+        assert_eq!(strip_phone_number("3630"), "3630");
+    }
+
+    #[test]
+    fn phone_numbers_are_normalized() {
+        assert_eq!(normalize_phone_number("+33 6 12 34 56 78", Some("33")), "+33612345678");
+        assert_eq!(normalize_phone_number("06 12 34 56 78", Some("+33")), "+33612345678");
+        assert_eq!(normalize_phone_number("0033612345678", None), "+33612345678");
+        assert_eq!(normalize_phone_number("0612345678", None), "0612345678");
+        assert_eq!(normalize_phone_number("3630", None), "3630");
+    }
+
+    #[test]
+    fn phone_numbers_are_encoded() {
+        //international number with spaces, odd digit count padded with F
+        assert_eq!(encode_phone_number("+33 6 12 34 56 78", None), "0B913316325476F8");
+        //national number, normalized with the default country code
+        assert_eq!(encode_phone_number("0612345678", Some("33")), "0B913316325476F8");
+        //national number without default country code, sent as dialled
+        assert_eq!(encode_phone_number("0612345678", None), "0A816021436587");
+        //00 prefix
+        assert_eq!(encode_phone_number("0033612345678", None), "0B913316325476F8");
+        //short codes are never normalized
+        assert_eq!(encode_phone_number("3630", Some("33")), "04816303");
+        assert_eq!(encode_phone_number("06123", Some("33")), "05816021F3");
+    }
+}
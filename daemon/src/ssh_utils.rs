@@ -10,7 +10,7 @@ use tokio::process::{Child, Command};
 use tokio::time::timeout;
 use crate::common;
 use crate::common::Error;
-use crate::common::Error::SshTunnelServiceError;
+use crate::common::Error::SshTunnelService;
 
 const SSH_CLOUD_SERVICE_ARGS: [&str; 1] = ["http"];
 
@@ -50,7 +50,7 @@ pub async fn setup_ssh_tunnel(config: &SshConfig, output_host: &IpAddr, output_p
     debug!("setup_ssh_tunnel: command issued");
 
     //reading tunnel url from stdout
-    let mut stdout = ssh_process.stdout.take().ok_or(common::Error::SystemCommandExecutionError)?;
+    let mut stdout = ssh_process.stdout.take().ok_or(common::Error::SystemCommandExecution)?;
     let tunnel_url = timeout(
         Duration::from_secs(config.tunnel_setup_timeout_sec),
         async {
@@ -73,17 +73,17 @@ pub async fn setup_ssh_tunnel(config: &SshConfig, output_host: &IpAddr, output_p
                         } else if let Some(captures) = regexp_error.captures(&s) {
                             let [error] = captures.extract().1.map(|s| s.to_string());
                             debug!("setup_ssh_tunnel: error: {}",error);
-                            break Err(SshTunnelServiceError(error));
+                            break Err(SshTunnelService(error));
                         } else {
                             debug!("setup_ssh_tunnel: url not yet read");
                         }
                     } else {
                         error!("setup_ssh_tunnel: cannot read process output");
-                        break Err(Error::SshTunnelUrlParsingError);
+                        break Err(Error::SshTunnelUrlParsing);
                     }
                 } else {
                     error!("setup_ssh_tunnel: cannot read process output");
-                    break Err(Error::SystemCommandExecutionError);
+                    break Err(Error::SystemCommandExecution);
                 }
             }
         },
@@ -91,8 +91,8 @@ pub async fn setup_ssh_tunnel(config: &SshConfig, output_host: &IpAddr, output_p
         error!("setup_ssh_tunnel: timeout while trying to read tunnel url");
         Err(Error::SshTunnelUrlSetupTimeout)
     });
-    if let Err(_) = tunnel_url {
-        let mut stderr = ssh_process.stderr.take().ok_or(common::Error::SystemCommandExecutionError).unwrap();
+    if tunnel_url.is_err() {
+        let mut stderr = ssh_process.stderr.take().ok_or(common::Error::SystemCommandExecution).unwrap();
         let mut buff = vec![0u8; 300];
         let len = stderr.read(&mut buff).await.unwrap();
         error!("setup_ssh_tunnel: stderr: {}",String::from_utf8(buff[0..len].to_vec()).unwrap());
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::fmt::{Display, Formatter};
+use std::fmt::{Display, Formatter, Write};
 use std::net::IpAddr;
 use std::process::Stdio;
 use std::time::Duration;
@@ -8,8 +8,10 @@ use tinyjson::JsonValue;
 use tokio::process::Command;
 use crate::common;
 use crate::common::Configuration;
-use crate::common::Error::QmiResponseParsingError;
+use crate::common::Error::QmiResponseParsing;
+use crate::sms_utils::SmsConfig;
 use crate::status::ServiceStatus::{Reachable, Unreachable};
+use crate::user::User;
 
 #[derive(Debug)]
 pub struct Status {
@@ -91,8 +93,8 @@ pub async fn get_status(configuration: &Configuration) -> common::Result<Status>
 
 async fn ping_domain(domain: &String) -> common::Result<()> {
     debug!("ping_domain: pinging {} ...", domain);
-    let dns_result = dns_lookup::lookup_host(&domain)?;
-    let ip = dns_result.into_iter().next().ok_or(common::Error::DomainNameResolutionError)?;
+    let dns_result = dns_lookup::lookup_host(domain)?;
+    let ip = dns_result.into_iter().next().ok_or(common::Error::DomainNameResolution)?;
     debug!("ping_domain: server ip address resolved {:?}", ip);
     let (_, duration) = surge_ping::ping(ip, &[0; 8]).await?;
     debug!("ping_domain: domain ping ok - duration: {:?}",duration);
@@ -138,6 +140,13 @@ pub struct QmiProvider {
 }
 
 impl QmiProvider {
+    pub fn new(config: &SmsConfig) -> Self {
+        QmiProvider {
+            qmi_binary: config.qmi_binary_file.to_string(),
+            qmi_device: config.qmi_modem_device.to_string(),
+        }
+    }
+
     pub async fn is_connected_to_internet(ip_addr: IpAddr) -> bool {
         debug!("is_connected_to_internet - pinging: {:?} ...",ip_addr);
         let mut i = 0;
@@ -149,7 +158,7 @@ impl QmiProvider {
                 }
                 Err(e) => {
                     error!("is_connected_to_internet - cannot ping internet: {:?}",e);
-                    i = i +1;
+                    i += 1;
                     if i > 3 {
                         error!("is_connected_to_internet - too many errors - considering internet is not available");
                         break false;
@@ -165,17 +174,10 @@ impl QmiProvider {
 
     async fn is_connected_to_lte(&self) -> common::Result<bool> {
         let system_info_string = self.qmi_command("--get-system-info",vec!()).await?;
-        let system_info_json: JsonValue = system_info_string.parse().map_err(|_| { QmiResponseParsingError("cannot parse --get-system-info response into json".to_string()) })?;
-        let service_status: &String = system_info_json["lte"]["service_status"].get().ok_or(QmiResponseParsingError("cannot read lte service status from system info".to_string()))?;
+        let system_info_json: JsonValue = system_info_string.parse().map_err(|_| { QmiResponseParsing("cannot parse --get-system-info response into json".to_string()) })?;
+        let service_status: &String = system_info_json["lte"]["service_status"].get().ok_or(QmiResponseParsing("cannot read lte service status from system info".to_string()))?;
         debug!("is_connected_to_lte - service status: {}",service_status);
-        let is_connected = match service_status.as_str() {
-            "available" => {
-                true
-            }
-            _ => {
-                false
-            }
-        };
+        let is_connected = service_status.as_str() == "available";
         Ok(is_connected)
     }
 
@@ -187,8 +189,8 @@ impl QmiProvider {
 
     async fn is_sim_locked(&self) -> common::Result<bool> {
         let sim_state_string = self.qmi_command("--uim-get-sim-state",vec!()).await?;
-        let sim_state_json: JsonValue = sim_state_string.parse().map_err(|_| { QmiResponseParsingError("cannot parse --uim-get-sim-state response into json".to_string()) })?;
-        let pin_status: &String = sim_state_json["pin1_status"].get().ok_or(QmiResponseParsingError("cannot read pin1_status from sim state info".to_string()))?;
+        let sim_state_json: JsonValue = sim_state_string.parse().map_err(|_| { QmiResponseParsing("cannot parse --uim-get-sim-state response into json".to_string()) })?;
+        let pin_status: &String = sim_state_json["pin1_status"].get().ok_or(QmiResponseParsing("cannot read pin1_status from sim state info".to_string()))?;
         debug!("is_sim_locked - pin status: {}",pin_status);
         let is_locked = match pin_status.as_str() {
             "disabled" => {
@@ -205,7 +207,7 @@ impl QmiProvider {
             }
             _ => {
                 error!("is_sim_locked - status not supported: {}",pin_status);
-                return Err(QmiResponseParsingError(format!("unsupported sim status: {}",pin_status)))
+                return Err(QmiResponseParsing(format!("unsupported sim status: {}",pin_status)))
             }
         };
         Ok(is_locked)
@@ -213,7 +215,7 @@ impl QmiProvider {
 
 
     ///Qualcomm MSM Interface allows router management
-    async fn qmi_command(&self, command: &str, command_args: Vec<&str>) -> common::Result<String> {
+    pub async fn qmi_command(&self, command: &str, command_args: Vec<&str>) -> common::Result<String> {
         debug!("qmi_command: running command: {:?}", command);
 
         let process = Command::new(&self.qmi_binary)
@@ -227,23 +229,30 @@ impl QmiProvider {
         let output = process.wait_with_output().await?;
 
         debug!("qmi_command: command status: {:?}", output.status.code());
-        output.status.success().then(|| ()).ok_or(common::Error::SystemCommandExecutionError)?;
-        let output_message = String::from_utf8(output.stdout).map_err(|_| { common::Error::SystemCommandExecutionError })?;
+        output.status.success().then_some(()).ok_or(common::Error::SystemCommandExecution)?;
+        let output_message = String::from_utf8(output.stdout).map_err(|_| { common::Error::SystemCommandExecution })?;
         debug!("qmi_command: command output message:\n {:?}", output_message);
         Ok(output_message)
     }
 }
 
 
-impl Display for Status {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Device: {}\n", self.device_status.to_string())?;
-        write!(f, "Services: Email: {} - Ssh Tunnel: {}\n", self.email_service_status.to_string(),self.ssh_tunnel_service_status.to_string())?;
+impl Status {
+    ///Status description restricted to the applications the user is allowed to tunnel to
+    pub fn to_string_for(&self, user: &User) -> String {
+        let mut description = String::new();
+        let _ = self.write_description(&mut description, |application| user.is_application_allowed(application));
+        description
+    }
+
+    fn write_description(&self, f: &mut dyn Write, is_visible: impl Fn(&str) -> bool) -> std::fmt::Result {
+        writeln!(f, "Device: {}", self.device_status)?;
+        writeln!(f, "Services: Email: {} - Ssh Tunnel: {}", self.email_service_status,self.ssh_tunnel_service_status)?;
         write!(f, "Apps: ")?;
-        let mut it = self.applications_status.iter().peekable();
+        let mut it = self.applications_status.iter().filter(|(application, _)| is_visible(application)).peekable();
         while let Some(status) = it.next()  {
-            write!(f, "{}: {}", status.0, status.1.to_string())?;
-            if !it.peek().is_none() {
+            write!(f, "{}: {}", status.0, status.1)?;
+            if it.peek().is_some() {
                 write!(f, " - ")?;
             }
         }
@@ -251,6 +260,12 @@ impl Display for Status {
     }
 }
 
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.write_description(f, |_| true)
+    }
+}
+
 impl Display for DeviceStatus {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let str = match self {
@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_DIGEST_PERIOD_SEC: u64 = 3600;
+pub const DEFAULT_MAX_MESSAGES_PER_SENDER: usize = 3;
+pub const DEFAULT_MAX_SENDERS: usize = 10;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestChannel {
+    #[default]
+    Email,
+    Sms,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct UnknownSenderConfig {
+    pub digest_channel: Option<DigestChannel>,
+    pub digest_period_sec: Option<u64>,
+    pub max_messages_per_sender: Option<usize>,
+    pub max_senders: Option<usize>,
+}
+
+#[derive(Default)]
+struct SenderAttempts {
+    count: u32,
+    messages: Vec<String>,
+}
+
+///Requests and calls from numbers not belonging to any user, gathered until they are reported to admins,
+/// the attempts of the numbers beyond the first ones being only counted so that a flood of spoofed numbers is not kept
+#[derive(Default)]
+pub struct UnknownSenderDigest {
+    senders: BTreeMap<String, SenderAttempts>,
+    other_attempts: u32,
+    last_report: Option<SystemTime>,
+}
+
+impl UnknownSenderDigest {
+    ///Records the message, only the first ones of each sender being kept until the next digest
+    pub fn record(&mut self, config: &UnknownSenderConfig, sender: &str, message: &str) {
+        if !self.senders.contains_key(sender) && self.senders.len() >= config.max_senders.unwrap_or(DEFAULT_MAX_SENDERS) {
+            self.other_attempts += 1;
+            debug!("record: attempt from {} only counted, {} attempts from other numbers",sender,self.other_attempts);
+            return;
+        }
+        let attempts = self.senders.entry(sender.to_string()).or_default();
+        attempts.count += 1;
+        if attempts.messages.len() < config.max_messages_per_sender.unwrap_or(DEFAULT_MAX_MESSAGES_PER_SENDER) {
+            attempts.messages.push(message.to_string());
+        }
+        debug!("record: {} attempts from {}",attempts.count,sender);
+    }
+
+    ///Returns the digest of the attempts recorded since the previous one, once the digest period has elapsed
+    pub fn take_digest(&mut self, config: &UnknownSenderConfig, now: SystemTime) -> Option<String> {
+        let period = Duration::from_secs(config.digest_period_sec.unwrap_or(DEFAULT_DIGEST_PERIOD_SEC));
+        let period_elapsed = self.last_report.and_then(|date| now.duration_since(date).ok()).map(|elapsed| elapsed >= period).unwrap_or(true);
+        if self.senders.is_empty() || !period_elapsed {
+            return None;
+        }
+        self.last_report = Some(now);
+        let mut digest: Vec<String> = std::mem::take(&mut self.senders).into_iter().map(|(sender, attempts)| {
+            let mut description = format!("{} ({} attempts):", sender, attempts.count);
+            for message in &attempts.messages {
+                description.push_str(&format!("\n- {}", message));
+            }
+            if attempts.count as usize > attempts.messages.len() {
+                description.push_str("\n- ...");
+            }
+            description
+        }).collect();
+        if self.other_attempts > 0 {
+            digest.push(format!("+{} others", std::mem::take(&mut self.other_attempts)));
+        }
+        Some(format!("Requests from unknown numbers:\n{}", digest.join("\n")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> UnknownSenderConfig {
+        UnknownSenderConfig {
+            digest_channel: None,
+            digest_period_sec: Some(3600),
+            max_messages_per_sender: Some(2),
+            max_senders: Some(2),
+        }
+    }
+
+    #[test]
+    fn digest_is_sent_once_per_period() {
+        let (config, now) = (config(), SystemTime::now());
+        let mut digest = UnknownSenderDigest::default();
+        assert_eq!(digest.take_digest(&config, now), None);
+        digest.record(&config, "+33699999999", "status");
+        assert_eq!(digest.take_digest(&config, now), Some("Requests from unknown numbers:\n+33699999999 (1 attempts):\n- status".to_string()));
+        digest.record(&config, "+33699999999", "close");
+        assert_eq!(digest.take_digest(&config, now + Duration::from_secs(3599)), None);
+        assert!(digest.take_digest(&config, now + Duration::from_secs(3600)).is_some());
+        assert_eq!(digest.take_digest(&config, now + Duration::from_secs(7200)), None);
+    }
+
+    #[test]
+    fn messages_and_senders_are_capped() {
+        let (config, now) = (config(), SystemTime::now());
+        let mut digest = UnknownSenderDigest::default();
+        for message in ["status", "open nas", "reboot"] {
+            digest.record(&config, "+33699999999", message);
+        }
+        digest.record(&config, "+33688888888", "(call)");
+        for i in 0..100 {
+            digest.record(&config, &format!("+336000000{:02}", i), "status");
+        }
+        //senders already in the digest are still recorded
+        digest.record(&config, "+33688888888", "status");
+        assert_eq!(digest.senders.len(), 2);
+        assert_eq!(digest.take_digest(&config, now), Some("Requests from unknown numbers:\n\
+            +33688888888 (2 attempts):\n- (call)\n- status\n\
+            +33699999999 (3 attempts):\n- status\n- open nas\n- ...\n\
+            +100 others".to_string()));
+        assert_eq!(digest.other_attempts, 0);
+    }
+}
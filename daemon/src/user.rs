@@ -1,10 +1,118 @@
 use serde::{Deserialize, Serialize};
 
+const OPERATOR_COMMANDS: [&str; 4] = ["open", "close", "status", "balance"];
+const VIEWER_COMMANDS: [&str; 2] = ["status", "balance"];
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    //all commands
+    Admin,
+    //tunnels, status and balance
+    Operator,
+    //status and balance
+    Viewer,
+}
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct User {
     pub name: String,
     pub phone_number: String,
     pub email: String,
+    //request run when the user calls, such as "status" or "open nas"
+    pub call: Option<String>,
+    //admins receive the alerts about the router
+    pub admin: Option<bool>,
+    //static code to append to sensitive commands, such as "open nas 1234"
+    pub pin: Option<String>,
+    //base32 secret of a time-based one-time password, as set up in an authenticator application
+    pub totp_secret: Option<String>,
+    //commands requiring the pin or totp code, defaults to open, reboot and shutdown
+    pub code_required_commands: Option<Vec<String>>,
+    //role defining the commands the user is allowed to run, all of them if not set
+    pub role: Option<Role>,
+    //commands the user is allowed to run, overriding the role ones
+    pub allowed_commands: Option<Vec<String>>,
+    //applications the user is allowed to tunnel to, all of them if not set
+    pub applications: Option<Vec<String>>,
+    //maximum duration of the tunnels open by the user, capped by the ssh tunnel timeout
+    pub tunnel_max_duration_sec: Option<u64>,
 }
 
+impl User {
+    pub fn is_admin(&self) -> bool {
+        self.admin.unwrap_or(false) || self.role == Some(Role::Admin)
+    }
+
+    pub fn is_command_allowed(&self, command: &str) -> bool {
+        if let Some(commands) = &self.allowed_commands {
+            return contains_command(commands, command);
+        }
+        match self.role {
+            None | Some(Role::Admin) => true,
+            Some(Role::Operator) => contains_command(&OPERATOR_COMMANDS, command),
+            Some(Role::Viewer) => contains_command(&VIEWER_COMMANDS, command),
+        }
+    }
+
+    pub fn is_application_allowed(&self, application: &str) -> bool {
+        self.applications.as_ref().map(|applications| applications.iter().any(|a| a == application)).unwrap_or(true)
+    }
+}
+
+fn contains_command<S: AsRef<str>>(commands: &[S], command: &str) -> bool {
+    commands.iter().any(|c| c.as_ref().eq_ignore_ascii_case(command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(role: Option<Role>, allowed_commands: Option<Vec<&str>>, applications: Option<Vec<&str>>) -> User {
+        User {
+            name: "alice".to_string(),
+            phone_number: "+33123456789".to_string(),
+            email: "alice@mail.com".to_string(),
+            call: None,
+            admin: None,
+            pin: None,
+            totp_secret: None,
+            code_required_commands: None,
+            role,
+            allowed_commands: allowed_commands.map(|commands| commands.iter().map(|c| c.to_string()).collect()),
+            applications: applications.map(|applications| applications.iter().map(|a| a.to_string()).collect()),
+            tunnel_max_duration_sec: None,
+        }
+    }
+
+    #[test]
+    fn commands_are_allowed_per_role() {
+        for command in ["open", "reboot", "audit"] {
+            assert!(user(None, None, None).is_command_allowed(command));
+            assert!(user(Some(Role::Admin), None, None).is_command_allowed(command));
+        }
+        let operator = user(Some(Role::Operator), None, None);
+        assert!(operator.is_command_allowed("Open"));
+        assert!(operator.is_command_allowed("balance"));
+        assert!(!operator.is_command_allowed("reboot"));
+        let viewer = user(Some(Role::Viewer), None, None);
+        assert!(viewer.is_command_allowed("STATUS"));
+        assert!(!viewer.is_command_allowed("open"));
+    }
+
+    #[test]
+    fn allowed_commands_override_role() {
+        let user = user(Some(Role::Admin), Some(vec!("status", "close")), None);
+        assert!(user.is_command_allowed("Close"));
+        assert!(!user.is_command_allowed("reboot"));
+        assert!(user.is_admin());
+    }
+
+    #[test]
+    fn applications_are_allowed() {
+        assert!(user(None, None, None).is_application_allowed("nas"));
+        let user = user(None, None, Some(vec!("camera")));
+        assert!(user.is_application_allowed("camera"));
+        assert!(!user.is_application_allowed("nas"));
+    }
+}
@@ -0,0 +1,103 @@
+use std::time::Duration;
+use log::{debug, error, info};
+use regex_lite::Regex;
+use serde::{Deserialize, Serialize};
+use crate::{common, pdu};
+use crate::common::Error::Ussd;
+use crate::modem::Modem;
+
+const USSD_TIMEOUT_SEC: u64 = 30;
+//gsm 7-bit alphabet, language unspecified
+const USSD_DEFAULT_DATA_CODING_SCHEME: u8 = 15;
+pub const DEFAULT_BALANCE_CHECK_PERIOD_SEC: u64 = 86400;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct UssdConfig {
+    pub balance_code: String,
+    pub balance_regex: Option<String>,
+    pub balance_alert_threshold: Option<f64>,
+    pub balance_check_period_sec: Option<u64>,
+}
+
+///Sends the ussd code to the network and returns its response text
+pub async fn send_ussd(modem: &dyn Modem, code: &str) -> common::Result<String> {
+    debug!("send_ussd: running AT+CUSD - code: {}",code);
+    //+CUSD=<n>,<str>,<dcs> - n: 1 -> response reported as a +CUSD result
+    let command = format!("AT+CUSD=1,\"{}\",{}", code, USSD_DEFAULT_DATA_CODING_SCHEME);
+    let response = modem.at_command_awaiting_result(command.as_str(), "+CUSD:", Duration::from_secs(USSD_TIMEOUT_SEC)).await.map_err(|e| {
+        error!("AT+CUSD failed - error: {:?}",e);
+        e
+    })?;
+    debug!("send_ussd: response received: {:?}",response);
+    let parameters = response.iter()
+        .find_map(|line| line.strip_prefix("+CUSD:"))
+        .ok_or_else(|| Ussd("no response received".to_string()))?;
+    let (status, text) = parse_ussd_response(parameters)?;
+    if status == 1 {
+        //the network expects an answer, such as a menu choice, the session is cancelled
+        debug!("send_ussd: cancelling ussd session");
+        if let Err(e) = modem.at_command("AT+CUSD=2").await {
+            error!("send_ussd: cannot cancel ussd session - error: {:?}",e);
+        }
+    }
+    info!("send_ussd: response: {:?}",text);
+    Ok(text)
+}
+
+///Reads the status and the decoded text of a +CUSD: <m>[,<str>,<dcs>] result
+fn parse_ussd_response(parameters: &str) -> common::Result<(u8, String)> {
+    let (status, string) = parameters.split_once(',').unwrap_or((parameters, ""));
+    let status = status.trim().parse::<u8>().map_err(|_| Ussd(format!("invalid response: {}", parameters)))?;
+    match status {
+        //0: no further action required, 1: further action required
+        0 | 1 => {}
+        2 => return Err(Ussd("session terminated by network".to_string())),
+        4 => return Err(Ussd("operation not supported".to_string())),
+        5 => return Err(Ussd("network timeout".to_string())),
+        _ => return Err(Ussd(format!("unexpected status: {}", status))),
+    }
+    //the string is quoted and may contain commas, the data coding scheme follows it
+    let (text, data_coding_scheme) = match string.rsplit_once(',') {
+        Some((text, data_coding_scheme)) if text.trim_end().ends_with('"') => {
+            (text, data_coding_scheme.trim().parse::<u8>().unwrap_or(USSD_DEFAULT_DATA_CODING_SCHEME))
+        }
+        _ => (string, USSD_DEFAULT_DATA_CODING_SCHEME),
+    };
+    let text = text.trim().trim_matches('"');
+    Ok((status, pdu::decode_ussd(text, data_coding_scheme)))
+}
+
+///Reads the balance amount from the first capture group of the regex, decimal separator being either a dot or a comma
+pub fn read_balance(text: &str, regex: &str) -> common::Result<f64> {
+    let regex = Regex::new(regex).map_err(|e| Ussd(format!("invalid balance regex: {}", e)))?;
+    let amount = regex.captures(text)
+        .and_then(|captures| captures.get(1))
+        .ok_or_else(|| Ussd(format!("balance not found in: {}", text)))?;
+    amount.as_str().replace(',', ".").parse::<f64>().map_err(|_| Ussd(format!("invalid balance amount: {}", amount.as_str())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ussd_response_is_parsed() {
+        assert_eq!(parse_ussd_response(r#"0,"Solde: 5,00 EUR",15"#).unwrap(), (0, "Solde: 5,00 EUR".to_string()));
+        //commas within the text, no data coding scheme
+        assert_eq!(parse_ussd_response(r#"1,"Menu: 1,2,3""#).unwrap(), (1, "Menu: 1,2,3".to_string()));
+        //ucs2 text
+        assert_eq!(parse_ussd_response(r#"0,"0053006F006C00640065",72"#).unwrap(), (0, "Solde".to_string()));
+        assert!(parse_ussd_response("2").is_err());
+        assert!(parse_ussd_response("4").is_err());
+        assert!(parse_ussd_response("x").is_err());
+    }
+
+    #[test]
+    fn balance_is_read() {
+        let regex = "([0-9]+[.,][0-9]+) EUR";
+        assert_eq!(read_balance("Votre solde est de 12,50 EUR", regex).unwrap(), 12.5);
+        assert_eq!(read_balance("Balance: 3.75 EUR", regex).unwrap(), 3.75);
+        assert!(read_balance("Service indisponible", regex).is_err());
+        assert!(read_balance("Votre solde est de 12,50 EUR", "(").is_err());
+    }
+}